@@ -56,20 +56,14 @@ impl CompressMode {
                     };
                 }
             }
-            CompressMode::Gzip(level) => {
-                if modes.contains(&"gzip") {
-                    return Some(Encoding::Gzip(level));
-                }
+            CompressMode::Gzip(level) if modes.contains(&"gzip") => {
+                return Some(Encoding::Gzip(level));
             }
-            CompressMode::Deflate(level) => {
-                if modes.contains(&"deflate") {
-                    return Some(Encoding::Deflate(level));
-                }
+            CompressMode::Deflate(level) if modes.contains(&"deflate") => {
+                return Some(Encoding::Deflate(level));
             }
-            CompressMode::Br(level) => {
-                if modes.contains(&"br") {
-                    return Some(Encoding::Br(level));
-                }
+            CompressMode::Br(level) if modes.contains(&"br") => {
+                return Some(Encoding::Br(level));
             }
             _ => {}
         }
@@ -84,6 +84,7 @@ pub struct Directive {
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Value {
     // name
     None,
@@ -206,6 +207,9 @@ impl<'a> LineParse<'a> {
             let mut line = line.to_string();
             line.pop();
             match line.split_whitespace().count() {
+                0 => {
+                    return Line::Error(Error::BlockStart);
+                }
                 // name {
                 1 => {
                     return Line::NameBlock(line.trim_end().to_string());
@@ -217,12 +221,15 @@ impl<'a> LineParse<'a> {
                     let value = sp.next().unwrap().to_string();
                     return Line::NameValueBlock(name, value);
                 }
-                len => {
-                    if len == 0 {
-                        return Line::Error(Error::BlockStart);
-                    } else {
-                        return Line::Error(Error::ValueLength);
-                    }
+                // map $`source` $`target` {
+                _ if line.split_whitespace().next() == Some("map") => {
+                    let cap = KV_REGEX.captures(line.trim_end()).unwrap();
+                    let name = cap.name("name").unwrap().as_str().to_string();
+                    let value = cap.name("value").unwrap().as_str().to_string();
+                    return Line::NameValueBlock(name, value);
+                }
+                _ => {
+                    return Line::Error(Error::ValueLength);
                 }
             };
         }
@@ -326,3 +333,11 @@ impl Display for ParseError {
         write!(f, "[line: {}] {}", self.0, msg)
     }
 }
+
+#[test]
+fn test_parse_block_value() {
+    assert!(Block::from_str("map $`a` $`b` {\n}").is_ok());
+    assert!(Block::from_str("location a {\n}").is_ok());
+    let err = Block::from_str("location a b {\n}").unwrap_err();
+    assert!(matches!(err.1, Error::ValueLength));
+}
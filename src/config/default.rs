@@ -6,6 +6,7 @@ use clap::{crate_name, crate_version};
 use hyper::Method as HttpMethod;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::time::Duration;
 
 // Package
 
@@ -21,7 +22,45 @@ pub fn config_path() -> String {
 
 // Server config
 
-pub const AUTH_MESSAGE: &str = "Basic realm=\"User Visible Realm\"";
+pub const AUTH_REALM: &str = "User Visible Realm";
+
+// Seconds a digest auth nonce can be used
+pub const AUTH_NONCE_EXPIRE: u64 = 300;
+// Nonce counts kept against replays
+pub const AUTH_NONCE_ENTRIES: usize = 100_000;
+
+// Seconds of clock skew allowed for JWT `exp` and `nbf`
+pub const JWT_LEEWAY: u64 = 60;
+
+// Failed attempts before a lockout
+pub const LOCKOUT_ATTEMPTS: u32 = 5;
+// The first lockout, doubled for each next one
+pub const LOCKOUT_TIME: Duration = Duration::from_secs(60);
+pub const LOCKOUT_MAX: Duration = Duration::from_secs(60 * 60);
+// Old entries are removed when there are this many
+pub const LOCKOUT_ENTRIES: usize = 100_000;
+
+pub const REAL_IP_HEADER: &str = "x-forwarded-for";
+
+// Time to receive the PROXY protocol header
+pub const PROXY_PROTOCOL_TIMEOUT: Duration = Duration::from_secs(5);
+// Answer of the `auth` request
+pub const AUTH_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const SESSION_COOKIE: &str = "see_session";
+// Seconds a login lasts
+pub const SESSION_EXPIRE: u64 = 43200;
+pub const SESSION_LOGOUT: &str = "/logout";
+// Bytes of a login form
+pub const SESSION_FORM_SIZE: usize = 16 * 1024;
+// Seconds to finish an OIDC login
+pub const SESSION_STATE_EXPIRE: u64 = 600;
+
+pub const OIDC_SCOPE: &str = "openid profile email";
+pub const OIDC_USER_CLAIM: &str = "sub";
+pub const OIDC_GROUPS_CLAIM: &str = "groups";
+// Keys are fetched again for an unknown signature, at most this often
+pub const OIDC_REFRESH: Duration = Duration::from_secs(60);
 
 pub const ALLOW_METHODS: [HttpMethod; 2] = [HttpMethod::GET, HttpMethod::HEAD];
 
@@ -38,6 +77,34 @@ pub const BUF_SIZE: usize = 16 * 1024;
 // Should be synchronized with src/var.rs
 pub const LOG_FORMAT: &str = "$`method` $`header_host`$`path`$`query` $`header_user-agent`";
 
+// Common Log Format
+pub const LOG_FORMAT_COMMON: &str =
+    "$`remote_addr` - $`remote_user|default:-` [$`time_local`] \"$`request|escape`\" $`status` $`body_bytes_sent`";
+
+// Combined Log Format
+pub const LOG_FORMAT_COMBINED: &str = "$`remote_addr` - $`remote_user|default:-` [$`time_local`] \"$`request|escape`\" \
+    $`status` $`body_bytes_sent` \"$`header_referer|escape|default:-`\" \"$`header_user-agent|escape|default:-`\"";
+
+// Default fields of `format json`, `key=variable` or `variable`
+pub const LOG_JSON_FIELDS: [&str; 10] = [
+    "time=time_iso8601",
+    "remote_addr",
+    "request_id",
+    "method",
+    "host",
+    "request_uri",
+    "status",
+    "body_bytes_sent",
+    "request_time",
+    "user_agent=header_user-agent",
+];
+
+// user
+pub const SYSLOG_FACILITY: u8 = 1;
+
+// How often watched files are checked for changes
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
 // Quick start
 
 pub fn bind_addr() -> SocketAddr {
@@ -58,6 +125,8 @@ pub fn quick_start_config(root: PathBuf, listen: SocketAddr) -> ServerConfig {
     ServerConfig {
         listen,
         tls: None,
+        proxy_protocol: None,
         sites: vec![site],
+        default: None,
     }
 }
@@ -6,12 +6,15 @@ mod setting;
 mod var;
 
 pub use setting::*;
-pub use var::Var;
+pub use var::{Context, Template, Var};
 
 use crate::conf::Block;
-use crate::exit;
 use crate::matcher::{HostMatcher, IpMatcher, LocationMatcher};
-use crate::option::{Auth, Compress, Directory, Index, Logger, Method, Proxy, Rewrite};
+use crate::option::{
+    Auth, Compress, Directory, GeoIp, Index, Logger, Maps, Method, Proxy, ProxyProtocol, RealIp,
+    Rewrite,
+};
+use crate::{exit, log_info};
 use hyper::header::{HeaderName, HeaderValue};
 use hyper::StatusCode;
 use parser::parse_server;
@@ -20,6 +23,7 @@ use std::fs;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
 use tokio_rustls::TlsAcceptor;
 
 pub type Headers = HashMap<HeaderName, Var<HeaderValue>>;
@@ -30,7 +34,10 @@ pub type ErrorPage = Setting<HashMap<StatusCode, Setting<PathBuf>>>;
 pub struct ServerConfig {
     pub listen: SocketAddr,
     pub tls: Option<TlsAcceptor>,
+    pub proxy_protocol: Option<ProxyProtocol>,
     pub sites: Vec<SiteConfig>,
+    // Position of the site with `listen ... default`
+    pub default: Option<usize>,
 }
 
 // Parse config from file
@@ -41,13 +48,26 @@ impl ServerConfig {
             .parent()
             .unwrap_or_else(|| exit!("Cannot get configuration file directory"));
 
-        let content = fs::read_to_string(&path)
+        let content = fs::read_to_string(path)
             .unwrap_or_else(|err| exit!("Read '{}' failed\n{:?}", path, err));
 
         let block = Block::from_str(&content)
             .unwrap_or_else(|err| exit!("Parsing config file failed\n{}", err));
 
-        parse_server(&block, config_dir).await
+        let configs = parse_server(&block, config_dir).await;
+        log_info!("Configuration loaded from '{}'", path);
+
+        configs
+    }
+
+    // The first site matching the host, or the default site
+    pub fn find_site(&self, host: Option<&str>) -> Option<usize> {
+        let position = match host {
+            Some(host) => self.sites.iter().position(|site| site.host.is_match(host)),
+            // Only sites without `host` match requests without a host
+            None => self.sites.iter().position(|site| site.host.is_empty()),
+        };
+        position.or(self.default)
     }
 }
 
@@ -69,6 +89,9 @@ pub struct SiteConfig {
     pub proxy: Setting<Proxy>,
     pub log: Setting<Logger>,
     pub ip: Setting<IpMatcher>,
+    pub real_ip: Setting<RealIp>,
+    pub geoip: Setting<GeoIp>,
+    pub maps: Arc<Maps>,
     pub location: Vec<Location>,
 }
 
@@ -94,11 +117,12 @@ pub struct Location {
 }
 
 impl SiteConfig {
-    pub fn merge(mut self, route: &str) -> Self {
+    pub fn merge(mut self, route: &str, ctx: &mut Context) -> Self {
         for item in self.location {
             if !item.location.is_match(route) {
                 continue;
             }
+            ctx.captures.extend(item.location.captures(route));
             if item.root.is_some() {
                 self.root = item.root;
             }
@@ -49,8 +49,9 @@ macro_rules! check_off {
     };
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub enum Setting<T> {
+    #[default]
     None,
     Off,
     Value(T),
@@ -77,12 +78,6 @@ impl<T> Setting<T> {
     }
 }
 
-impl<T> Default for Setting<T> {
-    fn default() -> Self {
-        Setting::None
-    }
-}
-
 impl<T: Default> Setting<T> {
     pub fn unwrap_or_default(self) -> T {
         match self {
@@ -5,7 +5,8 @@ use std::sync::Arc;
 use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
 use tokio_rustls::rustls::sign::{any_supported_type, CertifiedKey};
 use tokio_rustls::rustls::{
-    Certificate, NoClientAuth, PrivateKey, ResolvesServerCertUsingSNI, ServerConfig,
+    Certificate, ClientHello, NoClientAuth, PrivateKey, ResolvesServerCert,
+    ResolvesServerCertUsingSNI, ServerConfig,
 };
 use tokio_rustls::TlsAcceptor;
 
@@ -45,11 +46,28 @@ pub struct TLSContent {
     pub cert: PathBuf,
     pub key: PathBuf,
     pub sni: String,
+    // Certificate of the `default` site, used without SNI or for an unknown SNI
+    pub default: bool,
+}
+
+// Fall back to the certificate of the default site
+struct SniResolver {
+    sni: ResolvesServerCertUsingSNI,
+    default: Option<CertifiedKey>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        self.sni
+            .resolve(client_hello)
+            .or_else(|| self.default.clone())
+    }
 }
 
 pub fn create_sni_server_config(group: Vec<TLSContent>) -> Result<TlsAcceptor, String> {
     let mut config = ServerConfig::new(NoClientAuth::new());
     let mut sni = ResolvesServerCertUsingSNI::new();
+    let mut default = None;
 
     for content in group {
         let certs = load_certs(content.cert)?;
@@ -57,10 +75,13 @@ pub fn create_sni_server_config(group: Vec<TLSContent>) -> Result<TlsAcceptor, S
         let sign = any_supported_type(&keys.remove(0)).unwrap();
         let cert = CertifiedKey::new(certs, Arc::new(sign));
 
+        if content.default {
+            default = Some(cert.clone());
+        }
         sni.add(&content.sni, cert).unwrap();
     }
 
-    config.cert_resolver = Arc::new(sni);
+    config.cert_resolver = Arc::new(SniResolver { sni, default });
     config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
 
     Ok(TlsAcceptor::from(Arc::new(config)))
@@ -1,173 +1,500 @@
-use hyper::{Body, Request, Uri};
+use crate::option::{Geo, Maps};
+use crate::server::Connection;
+use crate::util;
+use hyper::header::HeaderName;
+use hyper::{Body, Request, StatusCode, Uri};
 use lazy_static::lazy_static;
-use regex::Regex;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::env;
+use std::hash::BuildHasher;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use time::OffsetDateTime;
 
-const REQUEST_PATH: &str = "$`path`";
-const REQUEST_QUERY: &str = "$`query`";
-const REQUEST_METHOD: &str = "$`method`";
-const REQUEST_VERSION: &str = "$`version`";
+type Getter = fn(&Request<Body>, &Context) -> String;
 
-const SERVER_TIME: &str = "$`time`";
+// Variables without parameters
+const SIMPLE: [(&str, Getter); 27] = [
+    ("path", |req, _| req.uri().path().to_string()),
+    ("query", |req, _| match req.uri().query() {
+        Some(query) => format!("?{}", query),
+        None => String::new(),
+    }),
+    ("method", |req, _| req.method().to_string()),
+    ("version", |req, _| format!("{:?}", req.version())),
+    ("request_uri", |req, _| match req.uri().path_and_query() {
+        Some(pq) => pq.to_string(),
+        None => req.uri().path().to_string(),
+    }),
+    ("host", |req, _| request_host(req)),
+    // Request line: GET /index.html HTTP/1.1
+    ("request", |req, _| {
+        let uri = match req.uri().path_and_query() {
+            Some(pq) => pq.as_str(),
+            None => req.uri().path(),
+        };
+        format!("{} {} {:?}", req.method(), uri, req.version())
+    }),
+    // Unix timestamp: 1391794831
+    ("time", |_, _| {
+        OffsetDateTime::now_utc().timestamp().to_string()
+    }),
+    // Unix timestamp with milliseconds: 1391794831.755
+    ("time_msec", |_, _| {
+        let dur = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        format_seconds(dur)
+    }),
+    // 2014-02-08T01:40:31+08:00
+    ("time_iso8601", |_, _| format_iso8601(util::now_local())),
+    // 2014-02-07T17:40:31+00:00
+    ("time_iso8601_utc", |_, _| {
+        format_iso8601(OffsetDateTime::now_utc())
+    }),
+    // Common log format: 08/Feb/2014:01:40:31 +0800
+    ("time_local", |_, _| {
+        util::now_local().format(TIME_LOCAL_FORMAT)
+    }),
+    ("remote_addr", |_, ctx| ctx.conn.remote.ip().to_string()),
+    ("remote_port", |_, ctx| ctx.conn.remote.port().to_string()),
+    ("server_addr", |_, ctx| ctx.conn.local.ip().to_string()),
+    ("server_port", |_, ctx| ctx.conn.local.port().to_string()),
+    ("scheme", |_, ctx| match ctx.conn.tls {
+        Some(_) => "https".to_string(),
+        None => "http".to_string(),
+    }),
+    ("request_id", |_, ctx| ctx.request_id.clone()),
+    // User name of `auth`
+    ("remote_user", |_, ctx| {
+        ctx.remote_user.clone().unwrap_or_default()
+    }),
+    // Looked up by `geoip`
+    ("geo_country", |_, ctx| {
+        ctx.geo.country.clone().unwrap_or_default()
+    }),
+    ("geo_asn", |_, ctx| match ctx.geo.asn {
+        Some(asn) => asn.to_string(),
+        None => String::new(),
+    }),
+    ("tls_sni", |_, ctx| match &ctx.conn.tls {
+        Some(tls) => tls.sni.clone(),
+        None => String::new(),
+    }),
+    ("tls_version", |_, ctx| match &ctx.conn.tls {
+        Some(tls) => tls.version.clone(),
+        None => String::new(),
+    }),
+    // Only available in the log
+    ("status", |_, ctx| match ctx.response.status {
+        Some(status) => status.as_str().to_string(),
+        None => String::new(),
+    }),
+    ("body_bytes_sent", |_, ctx| {
+        ctx.response.body_bytes_sent.to_string()
+    }),
+    // Seconds with milliseconds: 0.012
+    ("request_time", |_, ctx| format_seconds(ctx.start.elapsed())),
+    ("upstream_response_time", |_, ctx| {
+        match ctx.response.upstream_time {
+            Some(dur) => format_seconds(dur),
+            None => String::new(),
+        }
+    }),
+];
+
+const VAR_START: &str = "$`";
+const VAR_END: char = '`';
 
 const REQUEST_QUERY_KEY: &str = "query_";
 const REQUEST_HEADER_KEY: &str = "header_";
+const REQUEST_COOKIE_KEY: &str = "cookie_";
+const ENV_KEY: &str = "env_";
+const JWT_KEY: &str = "jwt_";
+const TIME_FORMAT_KEY: &str = "time:";
+const TIME_UTC_FORMAT_KEY: &str = "time_utc:";
+
+const TIME_LOCAL_FORMAT: &str = "%d/%b/%Y:%H:%M:%S %z";
+
+// Request related data that is not in the `Request`
+#[derive(Debug, Clone)]
+pub struct Context {
+    pub conn: Connection,
+    // Time when the request was received
+    pub start: Instant,
+    // Unique id of each request
+    pub request_id: String,
+    // Numbered and named groups of the matched regex location
+    // $`1` $`name`
+    pub captures: HashMap<String, String>,
+    // Variables defined by `map`
+    pub maps: Arc<Maps>,
+    // Set after `auth` passes
+    pub remote_user: Option<String>,
+    // Claims of the JWT checked by `auth`
+    pub jwt: HashMap<String, String>,
+    // Set when `geoip` is enabled
+    pub geo: Geo,
+    pub response: ResponseInfo,
+}
 
-lazy_static! {
-    static ref REGEX_QUERY: Regex = Regex::new(r"\$`query_([\w|-]+)`").unwrap();
-    static ref REGEX_HEADER: Regex = Regex::new(r"\$`header_([\w|-]+)`").unwrap();
+// Filled in after the response is created
+#[derive(Debug, Clone, Default)]
+pub struct ResponseInfo {
+    pub status: Option<StatusCode>,
+    pub body_bytes_sent: u64,
+    pub upstream_time: Option<Duration>,
+}
+
+impl Context {
+    pub fn new(conn: Connection) -> Self {
+        Self {
+            conn,
+            start: Instant::now(),
+            request_id: request_id(),
+            captures: HashMap::new(),
+            maps: Arc::default(),
+            remote_user: None,
+            jwt: HashMap::new(),
+            geo: Geo::default(),
+            response: ResponseInfo::default(),
+        }
+    }
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::new(Connection::default())
+    }
+}
+
+// 32 hex characters, unique within the process and unpredictable between processes
+fn request_id() -> String {
+    lazy_static! {
+        static ref STATE: RandomState = RandomState::new();
+        static ref COUNTER: AtomicU64 = AtomicU64::new(0);
+    }
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!(
+        "{:016x}{:016x}",
+        STATE.hash_one((n, 0)),
+        STATE.hash_one((n, 1))
+    )
 }
 
 #[derive(Debug, Clone)]
 pub enum Var<T> {
     None(T),
-    Some(String, Replace),
+    Some(Template),
 }
 
-impl<T: ToString> From<T> for Var<String> {
-    fn from(text: T) -> Self {
-        let text = text.to_string();
-        match Replace::new(&text) {
-            Some(rep) => Var::Some(text, rep),
-            None => Var::None(text),
+impl FromStr for Var<String> {
+    type Err = String;
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let template = Template::from_str(text)?;
+        if template.has_variable() {
+            Ok(Var::Some(template))
+        } else {
+            Ok(Var::None(text.to_string()))
         }
     }
 }
 
 impl<T> Var<T> {
-    pub fn map<F: FnOnce(String, Replace) -> T>(self, f: F) -> T {
+    pub fn map<F: FnOnce(Template) -> T>(self, f: F) -> T {
         match self {
             Var::None(x) => x,
-            Var::Some(s, r) => f(s, r),
+            Var::Some(t) => f(t),
         }
     }
 
     pub fn map_none<U, F: FnOnce(T) -> U>(self, f: F) -> Var<U> {
         match self {
             Var::None(x) => Var::None(f(x)),
-            Var::Some(s, r) => Var::Some(s, r),
+            Var::Some(t) => Var::Some(t),
         }
     }
 }
 
-#[derive(Debug, Clone, Default)]
-pub struct Replace {
-    path: bool,
-    query: bool,
-    method: bool,
-    version: bool,
-    time: bool,
-    query_key: bool,
-    header_key: bool,
+impl Var<String> {
+    pub fn render(&self, req: &Request<Body>, ctx: &Context) -> String {
+        match self {
+            Var::None(x) => x.clone(),
+            Var::Some(t) => t.render(req, ctx),
+        }
+    }
+
+    // Names of the referenced groups and `map` variables
+    pub fn names(&self) -> Vec<&str> {
+        match self {
+            Var::None(_) => vec![],
+            Var::Some(t) => {
+                t.0.iter()
+                    .filter_map(|seg| match seg {
+                        Segment::Variable(Variable::Named(name), _) => Some(name.as_str()),
+                        _ => None,
+                    })
+                    .collect()
+            }
+        }
+    }
 }
 
-impl Replace {
-    pub fn new(text: &str) -> Option<Self> {
-        let query: &Regex = &REGEX_QUERY;
-        let header: &Regex = &REGEX_HEADER;
-        let mut replace = Replace::default();
+// Text compiled from the configuration
+// Hello $`header_x-user|default:anon|html`
+#[derive(Debug, Clone)]
+pub struct Template(Vec<Segment>);
 
-        if text.contains(REQUEST_PATH) {
-            replace.path = true;
-        }
-        if text.contains(REQUEST_QUERY) {
-            replace.query = true;
-        }
-        if text.contains(REQUEST_METHOD) {
-            replace.method = true;
-        }
-        if text.contains(REQUEST_VERSION) {
-            replace.version = true;
-        }
-        if text.contains(SERVER_TIME) {
-            replace.time = true;
+#[derive(Debug, Clone)]
+enum Segment {
+    Text(String),
+    Variable(Variable, Vec<Filter>),
+}
+
+#[derive(Debug, Clone)]
+enum Variable {
+    Simple(Getter),
+    Query(String),
+    Header(HeaderName),
+    Cookie(String),
+    Env(String),
+    Jwt(String),
+    Time(String),
+    TimeUtc(String),
+    // Regex location groups
+    Named(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Filter {
+    // Used when the value is missing or empty
+    Default(String),
+    UrlEncode,
+    Lower,
+    Upper,
+    Json,
+    Html,
+    Escape,
+}
+
+impl FromStr for Template {
+    type Err = String;
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut segments = vec![];
+        let mut rest = text;
+
+        while let Some(start) = rest.find(VAR_START) {
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_string()));
+            }
+            rest = &rest[start + VAR_START.len()..];
+            let end = rest
+                .find(VAR_END)
+                .ok_or_else(|| format!("Missing '{}' in `{}`", VAR_END, text))?;
+            segments.push(Self::parse_variable(&rest[..end])?);
+            rest = &rest[end + 1..];
         }
-        if query.is_match(text) {
-            replace.query_key = true;
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_string()));
         }
-        if header.is_match(text) {
-            replace.header_key = true;
+
+        Ok(Self(segments))
+    }
+}
+
+impl Template {
+    // Parse a custom variable name without filters: $`name`
+    pub fn parse_name(text: &str) -> Result<String, String> {
+        let name = text
+            .strip_prefix(VAR_START)
+            .and_then(|s| s.strip_suffix(VAR_END))
+            .ok_or_else(|| format!("Cannot parse `{}` to variable", text))?;
+        match Self::parse_variable(name)? {
+            Segment::Variable(Variable::Named(name), filters) if filters.is_empty() => Ok(name),
+            _ => Err(format!("Cannot define built-in variable `{}`", text)),
         }
+    }
 
-        if replace.path
-            || replace.query
-            || replace.method
-            || replace.version
-            || replace.time
-            || replace.query_key
-            || replace.header_key
-        {
-            Some(replace)
+    // name|filter|filter:arg
+    fn parse_variable(s: &str) -> Result<Segment, String> {
+        let mut split = s.split('|');
+        let name = split.next().unwrap_or_default();
+
+        let variable = if let Some((_, get)) = SIMPLE.iter().find(|(n, _)| *n == name) {
+            Variable::Simple(*get)
+        } else if let Some(key) = name.strip_prefix(REQUEST_QUERY_KEY) {
+            Variable::Query(key.to_string())
+        } else if let Some(key) = name.strip_prefix(REQUEST_HEADER_KEY) {
+            Variable::Header(util::to_header_name(key)?)
+        } else if let Some(key) = name.strip_prefix(REQUEST_COOKIE_KEY) {
+            Variable::Cookie(key.to_string())
+        } else if let Some(key) = name.strip_prefix(ENV_KEY) {
+            Variable::Env(key.to_string())
+        } else if let Some(key) = name.strip_prefix(JWT_KEY) {
+            Variable::Jwt(key.to_string())
+        } else if let Some(format) = name.strip_prefix(TIME_FORMAT_KEY) {
+            util::check_strftime(format)?;
+            Variable::Time(format.to_string())
+        } else if let Some(format) = name.strip_prefix(TIME_UTC_FORMAT_KEY) {
+            util::check_strftime(format)?;
+            Variable::TimeUtc(format.to_string())
+        } else if !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            Variable::Named(name.to_string())
         } else {
-            None
-        }
+            return Err(format!("Unknown variable `{}{}{}`", VAR_START, s, VAR_END));
+        };
+
+        let filters = split
+            .map(|filter| match filter.split_once(':') {
+                Some(("default", value)) => Ok(Filter::Default(value.to_string())),
+                None => match filter {
+                    "default" => Ok(Filter::Default(String::new())),
+                    "urlencode" => Ok(Filter::UrlEncode),
+                    "lower" => Ok(Filter::Lower),
+                    "upper" => Ok(Filter::Upper),
+                    "json" => Ok(Filter::Json),
+                    "html" => Ok(Filter::Html),
+                    "escape" => Ok(Filter::Escape),
+                    _ => Err(filter),
+                },
+                _ => Err(filter),
+            })
+            .collect::<Result<Vec<Filter>, &str>>()
+            .map_err(|filter| {
+                format!(
+                    "Unknown filter `{}`, optional value: `default` `urlencode` `lower` `upper` `json` `html` `escape`",
+                    filter
+                )
+            })?;
+
+        Ok(Segment::Variable(variable, filters))
     }
 
-    pub fn replace(&self, mut source: String, req: &Request<Body>) -> String {
-        if self.path {
-            source = source.replace(REQUEST_PATH, req.uri().path());
-        }
+    fn has_variable(&self) -> bool {
+        self.0
+            .iter()
+            .any(|seg| matches!(seg, Segment::Variable(_, _)))
+    }
 
-        if self.query {
-            match req.uri().query() {
-                Some(query) => {
-                    source = source.replace(REQUEST_QUERY, &format!("?{}", query));
-                }
-                None => {
-                    source = source.replace(REQUEST_QUERY, "");
+    pub fn render(&self, req: &Request<Body>, ctx: &Context) -> String {
+        let mut s = String::new();
+        for seg in &self.0 {
+            match seg {
+                Segment::Text(text) => s.push_str(text),
+                Segment::Variable(variable, filters) => {
+                    let mut value = variable.value(req, ctx);
+                    for filter in filters {
+                        value = filter.apply(value);
+                    }
+                    s.push_str(&value);
                 }
             }
         }
+        s
+    }
+}
 
-        if self.method {
-            source = source.replace(REQUEST_METHOD, req.method().as_str());
-        }
-
-        if self.version {
-            source = source.replace(REQUEST_VERSION, &format!("{:?}", req.version()));
+impl Variable {
+    fn value(&self, req: &Request<Body>, ctx: &Context) -> String {
+        match self {
+            Variable::Simple(get) => get(req, ctx),
+            Variable::Query(key) => req.uri().get_query(key).unwrap_or_default().to_string(),
+            Variable::Header(name) => match req.headers().get(name) {
+                Some(h) => h.to_str().unwrap_or_default().to_string(),
+                None => String::new(),
+            },
+            Variable::Cookie(key) => util::get_cookie(req, key).unwrap_or_default().to_string(),
+            Variable::Env(key) => env::var(key).unwrap_or_default(),
+            Variable::Jwt(key) => ctx.jwt.get(key).cloned().unwrap_or_default(),
+            Variable::Time(format) => util::now_local().format(format),
+            Variable::TimeUtc(format) => OffsetDateTime::now_utc().format(format),
+            Variable::Named(name) => match ctx.captures.get(name) {
+                Some(value) => value.clone(),
+                None => match ctx.maps.get(name) {
+                    Some(map) => map.value(req, ctx),
+                    None => String::new(),
+                },
+            },
         }
+    }
+}
 
-        // TODO:
-        // Needs to be redesigned
-        // $`time_year` $`time_month` ...
-        if self.time {
-            match OffsetDateTime::try_now_local() {
-                Ok(dt) => {
-                    source = source.replace(SERVER_TIME, &dt.timestamp().to_string());
-                }
-                Err(_) => {
-                    source = source.replace(SERVER_TIME, "");
+impl Filter {
+    fn apply(&self, value: String) -> String {
+        match self {
+            Filter::Default(default) => {
+                if value.is_empty() {
+                    default.clone()
+                } else {
+                    value
                 }
-            };
+            }
+            Filter::UrlEncode => util::urlencode(&value),
+            Filter::Lower => value.to_lowercase(),
+            Filter::Upper => value.to_uppercase(),
+            Filter::Json => escape_json(&value),
+            Filter::Html => util::escape_html(&value),
+            Filter::Escape => escape_log(&value),
         }
+    }
+}
 
-        if self.query_key {
-            let reg: &Regex = &REGEX_QUERY;
-            for cap in reg.captures_iter(&source.clone()) {
-                if let Some(m) = cap.get(1) {
-                    let from = format!("$`{}{}`", REQUEST_QUERY_KEY, m.as_str());
-                    let to = req.uri().get_query(m.as_str()).unwrap_or_default();
-                    source = source.replace(from.as_str(), to);
-                }
-            }
+// Escape the content of a JSON string, without quotes
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
         }
+    }
+    escaped
+}
 
-        if self.header_key {
-            let reg: &Regex = &REGEX_HEADER;
-            for cap in reg.captures_iter(&source.clone()) {
-                if let Some(m) = cap.get(1) {
-                    let from = format!("$`{}{}`", REQUEST_HEADER_KEY, m.as_str());
-                    let to = match req.headers().get(m.as_str()) {
-                        Some(h) => h.to_str().unwrap(),
-                        None => "",
-                    };
-                    source = source.replace(from.as_str(), to);
-                }
+// Escape '"' '\\' and bytes outside the printable ASCII as \xHH
+fn escape_log(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'"' | b'\\' | 0..=0x1f | 0x7f..=0xff => {
+                escaped.push_str(&format!("\\x{:02X}", b));
             }
+            b => escaped.push(b as char),
         }
-
-        source
     }
+    escaped
+}
+
+fn format_seconds(dur: Duration) -> String {
+    format!("{}.{:03}", dur.as_secs(), dur.subsec_millis())
+}
+
+fn format_iso8601(dt: OffsetDateTime) -> String {
+    let offset = dt.offset().as_seconds();
+    let sign = if offset < 0 { '-' } else { '+' };
+    let offset = offset.abs();
+    format!(
+        "{}{}{:02}:{:02}",
+        dt.format("%Y-%m-%dT%H:%M:%S"),
+        sign,
+        offset / 3600,
+        offset % 3600 / 60
+    )
+}
+
+// Host without port, from the request line or the 'host' header
+fn request_host(req: &Request<Body>) -> String {
+    util::request_host(req)
+        .unwrap_or_default()
+        .unwrap_or_default()
 }
 
 trait GetQuery {
@@ -189,7 +516,167 @@ impl GetQuery for Uri {
     }
 }
 
+#[cfg(test)]
+fn render(text: &str, req: &Request<Body>, ctx: &Context) -> String {
+    Var::<String>::from_str(text)
+        .unwrap()
+        .map(|t| t.render(req, ctx))
+}
+
 #[test]
 fn test_var() {
-    let _ = Var::from("$`path`");
+    assert!(matches!(Var::<String>::from_str("text"), Ok(Var::None(_))));
+    assert!(matches!(
+        Var::<String>::from_str("$`path`"),
+        Ok(Var::Some(_))
+    ));
+    assert!(Var::<String>::from_str("$`time:%Y`").is_ok());
+    assert!(Var::<String>::from_str("$`time_utc:%Q`").is_err());
+    assert!(Var::<String>::from_str("$`path").is_err());
+    assert!(Var::<String>::from_str("$`a b`").is_err());
+    assert!(Var::<String>::from_str("$`path|none`").is_err());
+}
+
+#[test]
+fn test_var_capture() {
+    let req = Request::new(Body::empty());
+    let mut ctx = Context::default();
+    ctx.captures.insert("1".to_string(), "123".to_string());
+    ctx.captures.insert("id".to_string(), "456".to_string());
+
+    assert_eq!(render("/$`1`/$`id`/$`2`", &req, &ctx), "/123/456/");
+}
+
+#[test]
+fn test_var_single_pass() {
+    let req = Request::builder()
+        .uri("/a")
+        .header("x", "$`env_PATH`")
+        .body(Body::empty())
+        .unwrap();
+    let mut ctx = Context::default();
+    ctx.captures.insert("1".to_string(), "$`path`".to_string());
+
+    assert_eq!(render("$`header_x`", &req, &ctx), "$`env_PATH`");
+    assert_eq!(render("$`1`", &req, &ctx), "$`path`");
+    assert_eq!(render("$`header_x`$`path`", &req, &ctx), "$`env_PATH`/a");
+}
+
+#[test]
+fn test_var_request() {
+    use crate::server::TlsInfo;
+
+    let req = Request::builder()
+        .uri("/a/b?c=1")
+        .header(hyper::header::HOST, "Example.com:8080")
+        .header("cookie", "a=1; session=abc")
+        .body(Body::empty())
+        .unwrap();
+    let mut ctx = Context::new(Connection {
+        remote: "127.0.0.1:1234".parse().unwrap(),
+        local: "10.0.0.1:443".parse().unwrap(),
+        tls: Some(TlsInfo {
+            sni: "example.com".to_string(),
+            version: "TLSv1.3".to_string(),
+        }),
+    });
+    ctx.request_id = "id".to_string();
+    ctx.remote_user = Some("tom".to_string());
+    ctx.jwt.insert("role".to_string(), "admin".to_string());
+
+    let replace = |text: &str| render(text, &req, &ctx);
+
+    assert_eq!(
+        replace("$`remote_addr` $`remote_port` $`server_addr` $`server_port`"),
+        "127.0.0.1 1234 10.0.0.1 443"
+    );
+    assert_eq!(
+        replace("$`scheme`://$`host`$`request_uri`"),
+        "https://example.com/a/b?c=1"
+    );
+    assert_eq!(replace("$`request`"), "GET /a/b?c=1 HTTP/1.1");
+    assert_eq!(
+        replace("$`cookie_session`|$`cookie_none`"),
+        "abc|".to_string()
+    );
+    assert_eq!(
+        replace("$`tls_sni` $`tls_version` $`request_id` $`remote_user`"),
+        "example.com TLSv1.3 id tom"
+    );
+    assert_eq!(replace("$`env_SEE_TEST_NONE`"), "");
+    assert_eq!(replace("$`jwt_role`|$`jwt_none`"), "admin|");
+}
+
+#[test]
+fn test_var_filter() {
+    let req = Request::builder()
+        .uri("/a")
+        .header("x-user", "Tom \"T\"")
+        .body(Body::empty())
+        .unwrap();
+    let ctx = Context::default();
+    let replace = |text: &str| render(text, &req, &ctx);
+
+    assert_eq!(replace("$`header_x-none|default:anon`"), "anon");
+    assert_eq!(replace("$`header_x-none|default:a:b`"), "a:b");
+    assert_eq!(replace("$`header_X-User|default:anon`"), "Tom \"T\"");
+    assert_eq!(replace("$`header_x-user|lower`"), "tom \"t\"");
+    assert_eq!(replace("$`header_x-user|upper`"), "TOM \"T\"");
+    assert_eq!(replace("$`header_x-user|json`"), "Tom \\\"T\\\"");
+    assert_eq!(replace("$`header_x-user|html`"), "Tom &quot;T&quot;");
+    assert_eq!(replace("$`header_x-user|urlencode`"), "Tom%20%22T%22");
+    assert_eq!(
+        replace("$`header_x-none|default:<A>|lower|html`"),
+        "&lt;a&gt;"
+    );
+    assert_eq!(escape_json("a\nb\u{1}"), "a\\nb\\u0001");
+    assert_eq!(replace("$`header_x-user|escape`"), "Tom \\x22T\\x22");
+    assert_eq!(escape_log("a\\b\nç"), "a\\x5Cb\\x0A\\xC3\\xA7");
+}
+
+#[test]
+fn test_var_name() {
+    assert_eq!(Template::parse_name("$`is_mobile`").unwrap(), "is_mobile");
+    assert!(Template::parse_name("$`path`").is_err());
+    assert!(Template::parse_name("$`header_host`").is_err());
+    assert!(Template::parse_name("$`a|lower`").is_err());
+    assert!(Template::parse_name("a").is_err());
+
+    let var = Var::<String>::from_str("$`a`-$`path`-$`b|default:c`").unwrap();
+    assert_eq!(var.names(), vec!["a", "b"]);
+}
+
+#[test]
+fn test_request_id() {
+    let a = request_id();
+    let b = request_id();
+    assert_eq!(a.len(), 32);
+    assert_ne!(a, b);
+}
+
+#[test]
+fn test_var_time() {
+    let req = Request::new(Body::empty());
+    let ctx = Context::default();
+    let replace = |text: &str| render(text, &req, &ctx);
+
+    let year = OffsetDateTime::now_utc().year().to_string();
+    assert_eq!(replace("$`time_utc:%Y`"), year);
+    assert!(replace("$`time_iso8601_utc`").ends_with("+00:00"));
+    assert_eq!(replace("$`time_iso8601`").len(), 25);
+    assert_eq!(replace("$`time_local`").len(), 26);
+    assert!(replace("$`time`").parse::<i64>().is_ok());
+
+    let msec = replace("$`time_msec`");
+    assert_eq!(msec.split('.').nth(1).map(|s| s.len()), Some(3));
+}
+
+#[test]
+fn test_format_iso8601() {
+    let dt = OffsetDateTime::from_unix_timestamp(1391794831);
+    assert_eq!(format_iso8601(dt), "2014-02-07T17:40:31+00:00");
+    let dt = dt.to_offset(time::UtcOffset::hours(-5));
+    assert_eq!(format_iso8601(dt), "2014-02-07T12:40:31-05:00");
+    let dt = dt.to_offset(time::UtcOffset::minutes(330));
+    assert_eq!(format_iso8601(dt), "2014-02-07T23:10:31+05:30");
 }
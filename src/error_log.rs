@@ -0,0 +1,161 @@
+use crate::option::{LogBuffer, LogOutput, LogWriter};
+use crate::util;
+use lazy_static::lazy_static;
+use std::fmt::{self, Arguments, Display};
+use std::str::FromStr;
+use std::sync::RwLock;
+
+lazy_static! {
+    static ref ERROR_LOG: RwLock<ErrorLog> = RwLock::new(ErrorLog::default());
+}
+
+// Record a server-side failure in the error log
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        $crate::error_log::write($crate::error_log::Level::Error, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => {
+        $crate::error_log::write($crate::error_log::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        $crate::error_log::write($crate::error_log::Level::Info, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        $crate::error_log::write($crate::error_log::Level::Debug, format_args!($($arg)*))
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl FromStr for Level {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "debug" => Ok(Level::Debug),
+            "info" => Ok(Level::Info),
+            "warn" => Ok(Level::Warn),
+            "error" => Ok(Level::Error),
+            _ => Err(format!(
+                "Wrong error log level `{}`, optional value: `debug` `info` `warn` `error`",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        };
+        f.write_str(s)
+    }
+}
+
+// Print to stderr until `error_log` is configured
+#[derive(Debug)]
+pub struct ErrorLog {
+    level: Level,
+    writer: Option<LogWriter>,
+    // Syslog and journald have their own time and level
+    structured: bool,
+}
+
+impl Default for ErrorLog {
+    fn default() -> Self {
+        Self {
+            level: Level::Warn,
+            writer: None,
+            structured: false,
+        }
+    }
+}
+
+// Replace the global error log
+pub fn init(level: Level, output: LogOutput) {
+    let structured = output.is_structured();
+    let writer = LogWriter::spawn_error_log(output, &LogBuffer::default());
+    *ERROR_LOG.write().unwrap() = ErrorLog {
+        level,
+        writer: Some(writer),
+        structured,
+    };
+}
+
+pub fn write(level: Level, args: Arguments) {
+    let log = ERROR_LOG.read().unwrap();
+    if level < log.level {
+        return;
+    }
+
+    let message = escape(&args.to_string());
+    let line = match log.structured {
+        true => message,
+        false => format_line(level, &message),
+    };
+    match &log.writer {
+        Some(writer) => writer.send(level, line),
+        None => eprint!("{}", line),
+    }
+}
+
+// 2014/02/08 01:40:31 [error] message
+fn format_line(level: Level, message: &str) -> String {
+    format!(
+        "{} [{}] {}\n",
+        util::now_local().format("%Y/%m/%d %H:%M:%S"),
+        level,
+        message
+    )
+}
+
+// Messages may contain client input, control characters cannot start a new line
+fn escape(s: &str) -> String {
+    let mut rst = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c.is_control() {
+            true => rst.extend(c.escape_default()),
+            false => rst.push(c),
+        }
+    }
+    rst
+}
+
+#[test]
+fn test_error_log_level() {
+    assert_eq!("warn".parse::<Level>().unwrap(), Level::Warn);
+    assert!("trace".parse::<Level>().is_err());
+    assert!(Level::Error > Level::Warn && Level::Info > Level::Debug);
+
+    let line = format_line(Level::Error, "Open 'a' failed");
+    assert_eq!(line.len(), 20 + "[error] Open 'a' failed\n".len());
+    assert!(line.ends_with(" [error] Open 'a' failed\n"));
+
+    let message = escape(&format!(
+        "user '{}'",
+        "a\n2014/02/08 01:40:31 [info] b\u{7f}"
+    ));
+    assert_eq!(message, "user 'a\\n2014/02/08 01:40:31 [info] b\\u{7f}'");
+}
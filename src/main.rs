@@ -4,24 +4,28 @@ mod client;
 mod compress;
 mod conf;
 mod config;
+mod error_log;
 mod matcher;
 mod mime;
 mod option;
 mod server;
 mod util;
+mod watcher;
 
 use app::{run, RunType};
 use body::BodyStream;
-use config::{default, Headers, ServerConfig, Setting, SiteConfig, Var};
+use config::{default, Context, Headers, ServerConfig, Setting, SiteConfig, Var};
 use futures_util::future::join_all;
 use hyper::header::{
-    HeaderName, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, HOST,
+    HeaderName, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE,
     LOCATION, SERVER,
 };
 use hyper::Result as HyperResult;
 use hyper::{Body, HeaderMap, Request, Response, StatusCode, Version};
-use std::net::IpAddr;
+use option::LogBody;
+use server::Connection;
 use std::path::Path;
+use std::sync::Arc;
 use tokio::fs::{self, File};
 use tokio::net::TcpListener;
 use tokio::runtime;
@@ -44,10 +48,7 @@ async fn async_main() {
                 _ => format!(":{}", addr.port()),
             };
             println!("Serving path   : {}", path.display());
-            println!(
-                "Serving address: {}",
-                format!("http://{}{}", addr.ip(), port)
-            );
+            println!("Serving address: http://{}{}", addr.ip(), port);
             vec![config]
         }
         RunType::Config(config_path, is_test) => {
@@ -62,7 +63,40 @@ async fn async_main() {
             configs
         }
     };
-    bind_tcp(configs).await;
+    // Reopen log files after they are moved by logrotate
+    #[cfg(unix)]
+    tokio::spawn(option::reopen_on_signal());
+
+    tokio::select! {
+        _ = bind_tcp(configs) => {}
+        _ = shutdown_signal() => {}
+    }
+    // Write the queued logs before exit
+    option::flush_all().await;
+}
+
+// Wait for Ctrl-C or SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = term.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
 }
 
 async fn bind_tcp(configs: Vec<ServerConfig>) {
@@ -79,57 +113,38 @@ async fn bind_tcp(configs: Vec<ServerConfig>) {
     join_all(servers).await;
 }
 
+// The site of the request, or the status of the error response
 fn get_match_config(
     req: &Request<Body>,
-    configs: Vec<SiteConfig>,
-) -> Result<Option<SiteConfig>, ()> {
-    match req.version() {
-        Version::HTTP_2 => {
-            // todo
-            Ok(Some(configs[0].clone()))
-        }
-        Version::HTTP_11 => {
-            let host = match req.headers().get(HOST) {
-                Some(header) => {
-                    // Delete port
-                    header
-                        .to_str()
-                        .unwrap_or_default()
-                        .split(':')
-                        .next()
-                        .unwrap_or_default()
-                }
-                // A Host header field must be sent in all HTTP/1.1 request messages
-                // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Host
-                None => return Err(()),
-            };
-            // Use the host in config to match the header host
-            Ok(configs
-                .into_iter()
-                .find(|config| config.host.is_match(host)))
-        }
-        _ => {
-            // No header host
-            Ok(configs.into_iter().find(|config| config.host.is_empty()))
-        }
+    server: &ServerConfig,
+    sni_site: Option<usize>,
+) -> Result<SiteConfig, StatusCode> {
+    let host = util::request_host(req).map_err(|_| StatusCode::BAD_REQUEST)?;
+    // A Host header field must be sent in all HTTP/1.1 request messages
+    // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Host
+    if host.is_none() && req.version() >= Version::HTTP_11 {
+        return Err(StatusCode::BAD_REQUEST);
     }
+    let i = server
+        .find_site(host.as_deref())
+        .ok_or(StatusCode::FORBIDDEN)?;
+    // HTTP/2 clients reuse a connection for other hosts of the certificate,
+    // 421 makes them open a new one with the right SNI
+    if req.version() == Version::HTTP_2 && sni_site.map(|sni| sni != i) == Some(true) {
+        return Err(StatusCode::MISDIRECTED_REQUEST);
+    }
+    Ok(server.sites[i].clone())
 }
 
 pub async fn connect(
     req: Request<Body>,
-    remote: IpAddr,
-    configs: Vec<SiteConfig>,
-) -> HyperResult<Response<Body>> {
-    let mut config = match get_match_config(&req, configs) {
-        Ok(opt) => match opt {
-            Some(config) => config,
-            None => {
-                return Ok(Response::error(StatusCode::FORBIDDEN));
-            }
-        },
-        Err(_) => {
-            return Ok(Response::error(StatusCode::BAD_REQUEST));
-        }
+    conn: Connection,
+    server: Arc<ServerConfig>,
+    sni_site: Option<usize>,
+) -> HyperResult<Response<LogBody>> {
+    let mut config = match get_match_config(&req, &server, sni_site) {
+        Ok(config) => config,
+        Err(status) => return Ok(Response::error(status).map(LogBody::from)),
     };
 
     // Decode request path
@@ -138,45 +153,72 @@ pub async fn connect(
         .to_string();
 
     // Merge location to config
-    config = config.merge(&req_path);
-
-    let mut header_map = HeaderMap::new();
-    if let Setting::Value(headers) = config.headers.clone() {
-        headers_merge(&mut header_map, headers, &req);
+    let mut ctx = Context::new(conn);
+    if let Setting::Value(real_ip) = &config.real_ip {
+        ctx.conn.remote = real_ip.remote(&req, ctx.conn.remote);
+    }
+    if let Setting::Value(geoip) = &config.geoip {
+        ctx.geo = geoip.lookup(ctx.conn.remote.ip());
     }
+    ctx.maps = config.maps.clone();
+    config = config.merge(&req_path, &mut ctx);
+
+    // Headers and the log are rendered after auth fills the context,
+    // keep a copy of the request
+    let headers = match &config.headers {
+        Setting::Value(headers) => Some(headers.clone()),
+        _ => None,
+    };
+    let logger = match &config.log {
+        Setting::Value(logger) => Some(logger.clone()),
+        _ => None,
+    };
+    let head = match headers.is_some() || logger.is_some() {
+        true => Some(option::request_head(&req)),
+        false => None,
+    };
 
-    let mut res = handle(req, req_path, remote, config).await;
-    res.headers_mut().extend(header_map);
+    let mut res = handle(req, req_path, config, &mut ctx).await;
+    if let (Some(headers), Some(head)) = (headers, &head) {
+        headers_merge(res.headers_mut(), headers, head, &ctx);
+    }
 
     // Add server name for all responses
     res.headers_mut()
         .insert(SERVER, HeaderValue::from_static(default::SERVER_NAME));
 
-    Ok(res)
+    // Record request log
+    match (logger, head) {
+        (Some(logger), Some(head)) => Ok(logger.wrap(res, head, ctx)),
+        _ => Ok(res.map(LogBody::from)),
+    }
 }
 
 async fn handle(
-    req: Request<Body>,
+    mut req: Request<Body>,
     req_path: String,
-    ip: IpAddr,
     mut config: SiteConfig,
+    ctx: &mut Context,
 ) -> Response<Body> {
-    // Record request log
-    if let Setting::Value(logger) = &mut config.log {
-        logger.write(&req).await;
-    }
-
     // IP allow and deny
     if let Setting::Value(matcher) = &config.ip {
-        if !matcher.is_pass(ip) {
+        if !matcher.is_pass(ctx.conn.remote.ip(), ctx.geo.country.as_deref()) {
             return Response::error(StatusCode::FORBIDDEN);
         }
     }
 
     // HTTP auth
     if let Setting::Value(auth) = &config.auth {
-        if let Some(res) = auth.response(&req) {
-            return res;
+        match auth.response(&mut req, ctx).await {
+            Ok(identity) => {
+                ctx.remote_user = identity.user;
+                ctx.jwt = identity.claims;
+                for name in identity.remove_headers {
+                    req.headers_mut().remove(name);
+                }
+                req.headers_mut().extend(identity.headers);
+            }
+            Err(res) => return res,
         }
     }
 
@@ -184,7 +226,7 @@ async fn handle(
     if config.proxy.is_value() {
         let proxy = config.proxy.into_value();
         config.proxy = Setting::None;
-        return proxy.request(req, &config).await;
+        return proxy.request(req, &config, ctx).await;
     }
 
     // Not allowed request method
@@ -196,13 +238,13 @@ async fn handle(
 
     // echo: Output plain text
     if config.echo.is_value() {
-        let echo = config.echo.into_value().map(|s, r| r.replace(s, &req));
+        let echo = config.echo.into_value().map(|t| t.render(&req, ctx));
         return Response::new(Body::from(echo)).header(CONTENT_TYPE, mime::text_plain());
     }
 
     // rewrite
     if config.rewrite.is_value() {
-        return config.rewrite.into_value().response(&req);
+        return config.rewrite.into_value().response(&req, ctx);
     }
 
     let cur_path = format!(".{}", req_path);
@@ -242,7 +284,8 @@ async fn handle(
                     )
                     .await;
                 }
-                Err(_) => {
+                Err(err) => {
+                    log_error!("Open file '{}' failed: {}", path.display(), err);
                     return response_error_page(
                         req.headers().get(ACCEPT_ENCODING),
                         &config,
@@ -324,12 +367,32 @@ async fn handle(
     }
 }
 
-pub fn headers_merge(headers: &mut HeaderMap, new_headers: Headers, req: &Request<Body>) {
+pub fn headers_merge(
+    headers: &mut HeaderMap,
+    new_headers: Headers,
+    req: &Request<Body>,
+    ctx: &Context,
+) {
     for (name, value) in new_headers {
-        let val = value.map(|s, r| {
-            let v = r.replace(s, req);
-            HeaderValue::from_str(&v).unwrap()
-        });
+        let val = match value {
+            Var::None(val) => val,
+            Var::Some(t) => {
+                let v = t.render(req, ctx);
+                match HeaderValue::from_str(&v) {
+                    Ok(val) => val,
+                    Err(err) => {
+                        log_error!(
+                            "Invalid header '{}: {}': {}, request_id: {}",
+                            name,
+                            v,
+                            err,
+                            ctx.request_id
+                        );
+                        continue;
+                    }
+                }
+            }
+        };
         headers.insert(name, val);
     }
 }
@@ -340,10 +403,10 @@ pub async fn response_error_page(
     status: StatusCode,
 ) -> Response<Body> {
     if let Setting::Value(pages) = &config.error {
-        if let Some(setting) = pages.get(&status) {
-            if let Setting::Value(path) = setting {
-                if util::is_file(path).await {
-                    if let Ok(f) = File::open(&path).await {
+        if let Some(Setting::Value(path)) = pages.get(&status) {
+            if util::is_file(path).await {
+                match File::open(&path).await {
+                    Ok(f) => {
                         return response_file(
                             status,
                             f,
@@ -353,6 +416,9 @@ pub async fn response_error_page(
                         )
                         .await;
                     }
+                    Err(err) => {
+                        log_error!("Open error page '{}' failed: {}", path.display(), err);
+                    }
                 }
             }
         }
@@ -400,10 +466,13 @@ async fn response_file(
 
     let header = match encoding {
         Some(encoding) => (CONTENT_ENCODING, encoding.to_header_value()),
-        None => {
-            let meta = file.metadata().await.unwrap();
-            (CONTENT_LENGTH, HeaderValue::from(meta.len()))
-        }
+        None => match file.metadata().await {
+            Ok(meta) => (CONTENT_LENGTH, HeaderValue::from(meta.len())),
+            Err(err) => {
+                log_error!("Read file metadata failed: {}", err);
+                return Response::error(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        },
     };
 
     let body = BodyStream::new(encoding).file(file);
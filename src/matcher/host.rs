@@ -1,8 +1,9 @@
 use crate::matcher::WildcardMatcher;
+use crate::util::normalize_host;
 use std::collections::BTreeSet;
 
 // Match http header 'host'
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct HostMatcher {
     modes: Vec<MatchMode>,
 }
@@ -13,29 +14,22 @@ enum MatchMode {
     Wildcard(WildcardMatcher),
 }
 
-impl Default for HostMatcher {
-    fn default() -> HostMatcher {
-        HostMatcher {
-            modes: Vec::default(),
-        }
-    }
-}
-
 impl HostMatcher {
     // Creating a collection of host matchers
     pub fn new(items: Vec<&str>) -> Self {
         Self {
             modes: items
                 .into_iter()
-                .collect::<BTreeSet<&str>>()
+                .map(normalize_host)
+                .collect::<BTreeSet<String>>()
                 .into_iter()
                 .map(|item| {
                     if item.contains('*') {
                         // Use wildcard match: *.example.com
-                        MatchMode::Wildcard(WildcardMatcher::new(item))
+                        MatchMode::Wildcard(WildcardMatcher::new(&item))
                     } else {
                         // Plain Text: example.com
-                        MatchMode::Text(item.to_string())
+                        MatchMode::Text(item)
                     }
                 })
                 .collect::<Vec<MatchMode>>(),
@@ -65,6 +59,8 @@ impl HostMatcher {
             return true;
         }
 
+        let host = normalize_host(host);
+        let host = host.as_str();
         for matcher in &self.modes {
             match matcher {
                 MatchMode::Text(text) => {
@@ -106,4 +102,13 @@ mod test {
         assert!(!matcher.is_match("a.cn"));
         assert!(!matcher.is_match("a.a.cn"));
     }
+
+    #[test]
+    fn normalize() {
+        let matcher = HostMatcher::new(vec!["Example.com.", "*.Example.org"]);
+        assert!(matcher.is_match("example.com"));
+        assert!(matcher.is_match("EXAMPLE.COM."));
+        assert!(matcher.is_match("www.example.ORG."));
+        assert!(!matcher.is_match("example.net"));
+    }
 }
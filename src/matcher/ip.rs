@@ -1,61 +1,333 @@
+use super::ip_set::IpSet;
 use crate::matcher::WildcardMatcher;
 use crate::util;
+use crate::watcher::FileWatcher;
+use std::fmt;
 use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
 
-#[derive(Debug, Clone)]
-pub struct IpMatcher {
-    allow: Vec<MatchMode>,
-    deny: Vec<MatchMode>,
+// Clients on a dual-stack socket show up as `::ffff:a.b.c.d`
+pub fn normalize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        ip => ip,
+    }
+}
+
+// An address range, e.g. `10.0.0.0/8` or `fe80::/10`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, String> {
+        let (addr, prefix) = match addr {
+            // `::ffff:10.0.0.0/104` is `10.0.0.0/8`
+            IpAddr::V6(v6) if prefix >= 96 && v6.to_ipv4_mapped().is_some() => {
+                (normalize_ip(addr), prefix - 96)
+            }
+            addr => (addr, prefix),
+        };
+        if prefix > Self::bits(addr) {
+            return Err(format!("Wrong prefix length `{}` of `{}`", prefix, addr));
+        }
+        // The host bits are ignored
+        let addr = match addr {
+            IpAddr::V4(v4) => IpAddr::V4((u32::from(v4) & Self::mask(prefix, 32) as u32).into()),
+            IpAddr::V6(v6) => IpAddr::V6((u128::from(v6) & Self::mask(prefix, 128)).into()),
+        };
+        Ok(Self { addr, prefix })
+    }
+
+    fn bits(addr: IpAddr) -> u8 {
+        match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+
+    fn mask(prefix: u8, bits: u8) -> u128 {
+        match prefix {
+            0 => 0,
+            prefix => (u128::MAX << (128 - prefix)) >> (128 - bits),
+        }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, normalize_ip(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = Self::mask(self.prefix, 32) as u32;
+                u32::from(ip) & mask == u32::from(net)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                u128::from(ip) & Self::mask(self.prefix, 128) == u128::from(net)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    // A single address is a full-length prefix
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('/') {
+            Some((addr, prefix)) => {
+                let prefix = prefix
+                    .parse::<u8>()
+                    .map_err(|_| format!("Cannot parse `{}` to CIDR", s))?;
+                Self::new(util::to_ip_addr(addr)?, prefix)
+            }
+            None => {
+                let addr = util::to_ip_addr(s)?;
+                Self::new(addr, Self::bits(addr))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
 }
 
 #[derive(Debug, Clone)]
-enum MatchMode {
-    Ip(IpAddr),
+enum Pattern {
+    All,
+    Cidr(Cidr),
+    // Matched with the text of an IPv4 address
     Wildcard(WildcardMatcher),
+    // Reloaded when the file changes
+    File(FileWatcher<IpSet>),
+    // Country code looked up by `geoip`
+    Country(String),
 }
 
-impl MatchMode {
+impl Pattern {
     fn new(s: &str) -> Result<Self, String> {
-        if s.contains('*') {
-            Ok(Self::Wildcard(WildcardMatcher::new(s)))
-        } else {
-            Ok(Self::Ip(util::to_ip_addr(s)?))
+        if s == "all" || s == "*" {
+            return Ok(Pattern::All);
         }
+        if !s.contains('*') {
+            return Ok(Pattern::Cidr(s.parse()?));
+        }
+        // `192.168.*.*` is `192.168.0.0/16`
+        let parts = s.split('.').collect::<Vec<&str>>();
+        let fixed = parts.iter().take_while(|part| **part != "*").count();
+        if parts.len() == 4 && parts[fixed..].iter().all(|part| *part == "*") {
+            let mut octets = parts[..fixed].to_vec();
+            octets.resize(4, "0");
+            return Ok(Pattern::Cidr(Cidr::new(
+                util::to_ip_addr(&octets.join("."))?,
+                fixed as u8 * 8,
+            )?));
+        }
+        Ok(Pattern::Wildcard(WildcardMatcher::new(s)))
     }
 
-    fn is_match(&self, ip: &IpAddr) -> bool {
+    fn country(s: &str) -> Result<Self, String> {
+        if s.len() != 2 || !s.bytes().all(|b| b.is_ascii_alphabetic()) {
+            return Err(format!("Cannot parse `{}` to country code", s));
+        }
+        Ok(Pattern::Country(s.to_ascii_uppercase()))
+    }
+
+    fn is_match(&self, ip: IpAddr, country: Option<&str>) -> bool {
         match self {
-            MatchMode::Ip(m) => m == ip,
-            MatchMode::Wildcard(m) => m.is_match(&ip.to_string()),
+            Pattern::All => true,
+            Pattern::Cidr(cidr) => cidr.contains(ip),
+            Pattern::Wildcard(m) => m.is_match(&ip.to_string()),
+            Pattern::File(set) => set.get().contains(ip),
+            Pattern::Country(code) => country.map(|c| c.eq_ignore_ascii_case(code)) == Some(true),
         }
     }
 }
 
+// Rules are checked in order and the first match wins, like nginx.
+// Without a match, the client gets the opposite of the last rule.
+#[derive(Debug, Clone, Default)]
+pub struct IpMatcher {
+    rules: Vec<(bool, Pattern)>,
+}
+
 impl IpMatcher {
-    pub fn new(allow: Vec<&str>, deny: Vec<&str>) -> Result<Self, String> {
-        let mut a = vec![];
-        for item in allow {
-            a.push(MatchMode::new(item)?);
-        }
-        let mut d = vec![];
-        for item in deny {
-            d.push(MatchMode::new(item)?);
-        }
-        Ok(Self { allow: a, deny: d })
+    pub fn allow(mut self, s: &str) -> Result<Self, String> {
+        self.rules.push((true, Pattern::new(s)?));
+        Ok(self)
+    }
+
+    pub fn deny(mut self, s: &str) -> Result<Self, String> {
+        self.rules.push((false, Pattern::new(s)?));
+        Ok(self)
     }
 
-    pub fn is_pass(&self, ip: IpAddr) -> bool {
-        if !self.allow.is_empty() {
-            return self.allow.iter().any(|m| m.is_match(&ip));
+    // One address or CIDR per line
+    pub fn allow_file<P: AsRef<Path>>(mut self, path: P) -> Result<Self, String> {
+        let set = FileWatcher::new(path, IpSet::load)?;
+        self.rules.push((true, Pattern::File(set)));
+        Ok(self)
+    }
+
+    pub fn deny_file<P: AsRef<Path>>(mut self, path: P) -> Result<Self, String> {
+        let set = FileWatcher::new(path, IpSet::load)?;
+        self.rules.push((false, Pattern::File(set)));
+        Ok(self)
+    }
+
+    // ISO 3166-1 code, e.g. `US`
+    pub fn allow_country(mut self, code: &str) -> Result<Self, String> {
+        self.rules.push((true, Pattern::country(code)?));
+        Ok(self)
+    }
+
+    pub fn deny_country(mut self, code: &str) -> Result<Self, String> {
+        self.rules.push((false, Pattern::country(code)?));
+        Ok(self)
+    }
+
+    pub fn has_country(&self) -> bool {
+        self.rules
+            .iter()
+            .any(|(_, pattern)| matches!(pattern, Pattern::Country(_)))
+    }
+
+    // `country` is unknown without `geoip`
+    // Unlike nginx, a client matching no rule gets the opposite of the last rule
+    pub fn is_pass(&self, ip: IpAddr, country: Option<&str>) -> bool {
+        let ip = normalize_ip(ip);
+        for (allow, pattern) in &self.rules {
+            if pattern.is_match(ip, country) {
+                return *allow;
+            }
+        }
+        match self.rules.last() {
+            Some((allow, _)) => !allow,
+            None => true,
         }
-        self.deny.iter().all(|m| !m.is_match(&ip))
     }
 }
 
 #[cfg(test)]
 mod test {
-    // use super::*;
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr() {
+        let cidr = "10.1.2.3/8".parse::<Cidr>().unwrap();
+        assert_eq!(cidr.to_string(), "10.0.0.0/8");
+        assert!(cidr.contains(ip("10.255.0.1")));
+        assert!(cidr.contains(ip("::ffff:10.0.0.1")));
+        assert!(!cidr.contains(ip("11.0.0.1")));
+        assert!(!cidr.contains(ip("::a00:1")));
+
+        let cidr = "2001:db8::/32".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(ip("2001:db8:ffff::1")));
+        assert!(!cidr.contains(ip("2001:db9::1")));
+
+        assert_eq!(
+            "::ffff:192.168.0.0/112"
+                .parse::<Cidr>()
+                .unwrap()
+                .to_string(),
+            "192.168.0.0/16"
+        );
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("1.2.3.4")));
+        assert!("1.2.3.4".parse::<Cidr>().unwrap().contains(ip("1.2.3.4")));
+        assert!("1.2.3.4/33".parse::<Cidr>().is_err());
+        assert!("1.2.3.4/a".parse::<Cidr>().is_err());
+        assert!("::1/129".parse::<Cidr>().is_err());
+    }
 
     #[test]
-    fn ip() {}
+    fn ip_matcher() {
+        // First match wins
+        let matcher = IpMatcher::default()
+            .deny("192.168.1.1")
+            .unwrap()
+            .allow("192.168.0.0/16")
+            .unwrap()
+            .allow("2001:db8::/32")
+            .unwrap()
+            .deny("all")
+            .unwrap();
+        assert!(!matcher.is_pass(ip("192.168.1.1"), None));
+        assert!(!matcher.is_pass(ip("::ffff:192.168.1.1"), None));
+        assert!(matcher.is_pass(ip("192.168.1.2"), None));
+        assert!(matcher.is_pass(ip("::ffff:192.168.1.2"), None));
+        assert!(matcher.is_pass(ip("2001:db8::1"), None));
+        assert!(!matcher.is_pass(ip("10.0.0.1"), None));
+        assert!(!matcher.is_pass(ip("::1"), None));
+
+        // Without a match, the opposite of the last rule
+        let allow = IpMatcher::default().allow("127.0.0.1").unwrap();
+        assert!(allow.is_pass(ip("127.0.0.1"), None));
+        assert!(!allow.is_pass(ip("127.0.0.2"), None));
+        let deny = IpMatcher::default().deny("172.17.*.*").unwrap();
+        assert!(!deny.is_pass(ip("172.17.5.1"), None));
+        assert!(deny.is_pass(ip("172.18.5.1"), None));
+        assert!(IpMatcher::default().is_pass(ip("1.1.1.1"), None));
+
+        // Wildcards in the middle are matched as text
+        let matcher = IpMatcher::default().allow("10.*.0.1").unwrap();
+        assert!(matcher.is_pass(ip("10.20.0.1"), None));
+        assert!(!matcher.is_pass(ip("10.20.0.2"), None));
+
+        // Countries from `geoip`
+        let matcher = IpMatcher::default()
+            .allow("10.0.0.1")
+            .unwrap()
+            .deny_country("cn")
+            .unwrap()
+            .allow_country("US")
+            .unwrap()
+            .deny("all")
+            .unwrap();
+        assert!(matcher.has_country());
+        assert!(matcher.is_pass(ip("8.8.8.8"), Some("US")));
+        assert!(!matcher.is_pass(ip("1.1.1.1"), Some("CN")));
+        assert!(matcher.is_pass(ip("10.0.0.1"), Some("CN")));
+        assert!(!matcher.is_pass(ip("8.8.8.8"), None));
+        assert!(!IpMatcher::default().allow("all").unwrap().has_country());
+        assert!(IpMatcher::default().allow_country("USA").is_err());
+
+        assert!(IpMatcher::default().allow("10.0.0.0/40").is_err());
+        assert!(IpMatcher::default().deny_file("./test-none.txt").is_err());
+        assert!(IpMatcher::default().deny("example.com").is_err());
+    }
+
+    #[tokio::test]
+    async fn ip_file() {
+        let path = "./test-ip-deny.txt";
+        std::fs::write(path, "10.0.0.0/8\n192.168.1.1\n").unwrap();
+        let matcher = IpMatcher::default()
+            .allow("10.1.1.1")
+            .unwrap()
+            .deny_file(path)
+            .unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert!(matcher.is_pass(ip("10.1.1.1"), None));
+        assert!(!matcher.is_pass(ip("10.1.1.2"), None));
+        assert!(!matcher.is_pass(ip("192.168.1.1"), None));
+        assert!(matcher.is_pass(ip("192.168.1.2"), None));
+    }
 }
@@ -0,0 +1,129 @@
+use super::ip::{normalize_ip, Cidr};
+use std::net::IpAddr;
+
+// Binary trie of address prefixes
+#[derive(Debug, Clone)]
+struct Trie {
+    // Children by the next bit, 0 is no child
+    nodes: Vec<[u32; 2]>,
+    // A prefix ends at the node
+    ends: Vec<bool>,
+}
+
+impl Trie {
+    fn new() -> Self {
+        Self {
+            nodes: vec![[0, 0]],
+            ends: vec![false],
+        }
+    }
+
+    // The highest `prefix` of `bits` bits are used
+    fn insert(&mut self, value: u128, bits: u8, prefix: u8) {
+        let mut node = 0;
+        for i in 0..prefix {
+            if self.ends[node] {
+                // Already covered by a shorter prefix
+                return;
+            }
+            let bit = ((value >> (bits - 1 - i)) & 1) as usize;
+            if self.nodes[node][bit] == 0 {
+                self.nodes.push([0, 0]);
+                self.ends.push(false);
+                self.nodes[node][bit] = (self.nodes.len() - 1) as u32;
+            }
+            node = self.nodes[node][bit] as usize;
+        }
+        self.ends[node] = true;
+    }
+
+    fn contains(&self, value: u128, bits: u8) -> bool {
+        let mut node = 0;
+        for i in 0..bits {
+            if self.ends[node] {
+                return true;
+            }
+            let bit = ((value >> (bits - 1 - i)) & 1) as usize;
+            match self.nodes[node][bit] {
+                0 => return false,
+                next => node = next as usize,
+            }
+        }
+        self.ends[node]
+    }
+}
+
+// Addresses and CIDR ranges, one per line
+#[derive(Debug, Clone)]
+pub struct IpSet {
+    v4: Trie,
+    v6: Trie,
+}
+
+impl IpSet {
+    pub fn new(s: &str) -> Result<Self, String> {
+        let mut set = Self {
+            v4: Trie::new(),
+            v6: Trie::new(),
+        };
+        for (i, line) in s.lines().enumerate() {
+            // Comments start with `#`
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let cidr = line
+                .parse::<Cidr>()
+                .map_err(|err| format!("Line {}: {}", i + 1, err))?;
+            set.insert(cidr);
+        }
+        Ok(set)
+    }
+
+    pub fn load(data: Vec<u8>) -> Result<Self, String> {
+        let s = String::from_utf8(data).map_err(|err| err.to_string())?;
+        Self::new(&s)
+    }
+
+    fn insert(&mut self, cidr: Cidr) {
+        match cidr.addr() {
+            IpAddr::V4(v4) => self.v4.insert(u32::from(v4) as u128, 32, cidr.prefix()),
+            IpAddr::V6(v6) => self.v6.insert(u128::from(v6), 128, cidr.prefix()),
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match normalize_ip(ip) {
+            IpAddr::V4(v4) => self.v4.contains(u32::from(v4) as u128, 32),
+            IpAddr::V6(v6) => self.v6.contains(u128::from(v6), 128),
+        }
+    }
+}
+
+#[test]
+fn test_ip_set() {
+    let set = IpSet::new(
+        "# Blocked\n10.0.0.0/8\n192.168.1.1  # one host\n\n10.1.0.0/16\n2001:db8::/32\n::ffff:172.16.0.0/108\n",
+    )
+    .unwrap();
+    let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+    assert!(set.contains(ip("10.200.3.4")));
+    assert!(set.contains(ip("::ffff:10.0.0.1")));
+    assert!(set.contains(ip("192.168.1.1")));
+    assert!(!set.contains(ip("192.168.1.2")));
+    assert!(set.contains(ip("172.31.255.255")));
+    assert!(!set.contains(ip("172.32.0.0")));
+    assert!(set.contains(ip("2001:db8::1")));
+    assert!(!set.contains(ip("2001:db9::1")));
+    assert!(!set.contains(ip("::1")));
+
+    let all = IpSet::new("0.0.0.0/0\n").unwrap();
+    assert!(all.contains(ip("8.8.8.8")));
+    assert!(!all.contains(ip("::2")));
+    assert!(!IpSet::new("").unwrap().contains(ip("1.1.1.1")));
+
+    assert_eq!(
+        IpSet::new("10.0.0.1\n\n10.0.0.256\n").unwrap_err(),
+        "Line 3: Cannot parse `10.0.0.256` to IP addr"
+    );
+}
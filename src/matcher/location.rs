@@ -43,6 +43,48 @@ impl LocationMatcher {
             MatchMode::End(s) => path.ends_with(s),
         }
     }
+
+    // Names of the groups set by `captures`
+    pub fn capture_names(&self) -> Vec<String> {
+        let reg = match &self.0 {
+            MatchMode::Regex(reg) => reg,
+            _ => return vec![],
+        };
+        let mut vec = vec![];
+        for (i, name) in reg.capture_names().enumerate() {
+            if let Some(name) = name {
+                vec.push(name.to_string());
+            }
+            vec.push(i.to_string());
+        }
+        vec
+    }
+
+    // Get the numbered and named groups of the regular expression
+    // Other modes have no captures
+    pub fn captures(&self, path: &str) -> Vec<(String, String)> {
+        let reg = match &self.0 {
+            MatchMode::Regex(reg) => reg,
+            _ => return vec![],
+        };
+        let caps = match reg.captures(path) {
+            Some(caps) => caps,
+            None => return vec![],
+        };
+
+        let mut vec = vec![];
+        for (i, name) in reg.capture_names().enumerate() {
+            let value = caps
+                .get(i)
+                .map(|m| m.as_str().to_string())
+                .unwrap_or_default();
+            if let Some(name) = name {
+                vec.push((name.to_string(), value.clone()));
+            }
+            vec.push((i.to_string(), value));
+        }
+        vec
+    }
 }
 
 #[cfg(test)]
@@ -73,6 +115,20 @@ mod test {
         assert!(matcher.is_match("/test/a/b"));
     }
 
+    #[test]
+    fn captures() {
+        let matcher = LocationMatcher::regex(r"^/user/(\d+)/(?P<name>\w+)$").unwrap();
+        let caps = matcher.captures("/user/123/abc");
+        assert!(caps.contains(&("0".to_string(), "/user/123/abc".to_string())));
+        assert!(caps.contains(&("1".to_string(), "123".to_string())));
+        assert!(caps.contains(&("2".to_string(), "abc".to_string())));
+        assert!(caps.contains(&("name".to_string(), "abc".to_string())));
+        assert!(matcher.captures("/user/abc").is_empty());
+        assert!(LocationMatcher::start("/user")
+            .captures("/user/1")
+            .is_empty());
+    }
+
     #[test]
     fn glob() {
         let matcher = LocationMatcher::glob("/test/*").unwrap();
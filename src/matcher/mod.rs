@@ -1,9 +1,10 @@
 mod host;
 mod ip;
+mod ip_set;
 mod location;
 mod wildcard;
 
 pub use host::HostMatcher;
-pub use ip::IpMatcher;
+pub use ip::{normalize_ip, Cidr, IpMatcher};
 pub use location::LocationMatcher;
 pub use wildcard::WildcardMatcher;
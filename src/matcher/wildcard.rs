@@ -25,7 +25,7 @@ impl WildcardMatcher {
                         }
                         None => return false,
                     }
-                    while let Some(n) = chars.next() {
+                    for n in chars.by_ref() {
                         if n == '.' {
                             dot = true;
                             break;
@@ -0,0 +1,182 @@
+use crate::config::{default, Context};
+use crate::{client, log_error, ResponseExt};
+use hyper::header::{HeaderName, HeaderValue, CONTENT_LENGTH, HOST, TRANSFER_ENCODING};
+use hyper::{Body, HeaderMap, Request, Response, StatusCode, Uri};
+use std::time::Duration;
+use tokio::time::timeout;
+
+const ORIGINAL_URI: &str = "x-original-uri";
+
+// Ask an external service whether the request is allowed, like nginx `auth_request`
+#[derive(Debug, Clone)]
+pub struct AuthRequest {
+    url: Uri,
+    // Copied from the answer to the request
+    headers: Vec<HeaderName>,
+    timeout: Duration,
+}
+
+impl AuthRequest {
+    pub fn new(url: Uri, headers: Vec<HeaderName>) -> Result<Self, String> {
+        if url.scheme().is_none() || url.authority().is_none() {
+            return Err(format!("Expected an absolute url, e.g. `http://{}`", url));
+        }
+        Ok(Self {
+            url,
+            headers,
+            timeout: default::AUTH_REQUEST_TIMEOUT,
+        })
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // Removed from the request before the copied values are added
+    pub fn header_names(&self) -> &[HeaderName] {
+        &self.headers
+    }
+
+    // Get the chosen headers of a 2xx answer
+    pub async fn check(
+        &self,
+        req: &Request<Body>,
+        ctx: &Context,
+    ) -> Result<HeaderMap, Response<Body>> {
+        let mut sub = Request::new(Body::empty());
+        *sub.method_mut() = req.method().clone();
+        *sub.uri_mut() = self.url.clone();
+        *sub.headers_mut() = req.headers().clone();
+
+        // The body is not sent
+        let headers = sub.headers_mut();
+        headers.remove(HOST);
+        headers.remove(CONTENT_LENGTH);
+        headers.remove(TRANSFER_ENCODING);
+        if let Ok(uri) = HeaderValue::from_str(&req.uri().to_string()) {
+            headers.insert(ORIGINAL_URI, uri);
+        }
+
+        let res = match timeout(self.timeout, client::request(sub)).await {
+            Ok(Ok(res)) => res,
+            Ok(Err(err)) => {
+                log_error!(
+                    "Auth request to '{}' failed: {}, request_id: {}",
+                    self.url,
+                    err,
+                    ctx.request_id
+                );
+                return Err(Response::error(StatusCode::INTERNAL_SERVER_ERROR));
+            }
+            Err(_) => {
+                log_error!(
+                    "Auth request to '{}' timed out, request_id: {}",
+                    self.url,
+                    ctx.request_id
+                );
+                return Err(Response::error(StatusCode::GATEWAY_TIMEOUT));
+            }
+        };
+
+        match Response::status(&res) {
+            status if status.is_success() => {
+                let mut headers = HeaderMap::new();
+                for name in &self.headers {
+                    for value in res.headers().get_all(name) {
+                        headers.append(name.clone(), value.clone());
+                    }
+                }
+                Ok(headers)
+            }
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(res),
+            status => {
+                log_error!(
+                    "Auth request to '{}' returned unexpected status {}, request_id: {}",
+                    self.url,
+                    status,
+                    ctx.request_id
+                );
+                Err(Response::error(StatusCode::INTERNAL_SERVER_ERROR))
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_auth_request() {
+    use hyper::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::Server;
+    use std::convert::Infallible;
+
+    // Allow `token`, and report the original request
+    let service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+            let res = Response::builder();
+            let res = match req.headers().get(AUTHORIZATION) {
+                Some(val) if val == "token" => res
+                    .header("x-user", "tom")
+                    .header("x-role", "admin")
+                    .header(
+                        "x-info",
+                        format!(
+                            "{} {}",
+                            req.method(),
+                            req.headers()[ORIGINAL_URI].to_str().unwrap()
+                        ),
+                    ),
+                Some(val) if val == "down" => res.status(StatusCode::SERVICE_UNAVAILABLE),
+                Some(val) if val == "slow" => {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    res
+                }
+                _ => res
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header(WWW_AUTHENTICATE, "Bearer"),
+            };
+            Ok::<_, Infallible>(res.body(Body::empty()).unwrap())
+        }))
+    });
+    let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(service);
+    let url = format!("http://{}/check", server.local_addr());
+    tokio::spawn(server);
+
+    let auth = AuthRequest::new(
+        url.parse().unwrap(),
+        vec![
+            HeaderName::from_static("x-user"),
+            HeaderName::from_static("x-info"),
+            HeaderName::from_static("x-none"),
+        ],
+    )
+    .unwrap();
+    assert!(AuthRequest::new("/check".parse().unwrap(), vec![]).is_err());
+    let req = |authorization: &str| {
+        Request::builder()
+            .method("POST")
+            .uri("/a?b=1")
+            .header(AUTHORIZATION, authorization)
+            .body(Body::from("data"))
+            .unwrap()
+    };
+    let ctx = Context::default();
+
+    let headers = auth.check(&req("token"), &ctx).await.unwrap();
+    assert_eq!(headers.len(), 2);
+    assert_eq!(headers["x-user"], "tom");
+    assert!(!headers.contains_key("x-none"));
+    assert_eq!(headers["x-info"], "POST /a?b=1");
+    assert_eq!(auth.header_names().len(), 3);
+
+    let res = auth.check(&req("a"), &ctx).await.unwrap_err();
+    assert_eq!(Response::status(&res), StatusCode::UNAUTHORIZED);
+    assert_eq!(res.headers()[WWW_AUTHENTICATE], "Bearer");
+
+    let res = auth.check(&req("down"), &ctx).await.unwrap_err();
+    assert_eq!(Response::status(&res), StatusCode::INTERNAL_SERVER_ERROR);
+
+    let auth = auth.timeout(Duration::from_millis(50));
+    let res = auth.check(&req("slow"), &ctx).await.unwrap_err();
+    assert_eq!(Response::status(&res), StatusCode::GATEWAY_TIMEOUT);
+}
@@ -173,6 +173,7 @@ fn format_size(n: u64) -> String {
 }
 
 #[test]
+#[allow(clippy::identity_op, clippy::legacy_numeric_constants)]
 fn test_format_size() {
     assert_eq!(format_size(0), "0 B");
     assert_eq!(format_size(1), "1 B");
@@ -0,0 +1,207 @@
+use crate::matcher::normalize_ip;
+use crate::watcher::FileWatcher;
+use maxminddb::{geoip2, Reader};
+use std::net::IpAddr;
+use std::path::Path;
+
+// Country and ASN of an address
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Geo {
+    // ISO 3166-1 code, e.g. `US`
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+// MaxMind databases, e.g. GeoLite2-Country and GeoLite2-ASN
+#[derive(Debug, Clone)]
+pub struct GeoIp {
+    databases: Vec<FileWatcher<Reader<Vec<u8>>>>,
+}
+
+impl GeoIp {
+    pub fn new<P: AsRef<Path>>(paths: &[P]) -> Result<Self, String> {
+        let databases = paths
+            .iter()
+            .map(|path| FileWatcher::new(path, load))
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Self { databases })
+    }
+
+    // The first database with a value wins
+    pub fn lookup(&self, ip: IpAddr) -> Geo {
+        let ip = normalize_ip(ip);
+        let mut geo = Geo::default();
+        for db in &self.databases {
+            let reader = db.get();
+            // An IPv4 database cannot look up IPv6 addresses
+            if reader.metadata.ip_version == 4 && ip.is_ipv6() {
+                continue;
+            }
+            if geo.country.is_none() {
+                if let Ok(country) = reader.lookup::<geoip2::Country>(ip) {
+                    geo.country = country
+                        .country
+                        .and_then(|country| country.iso_code)
+                        .map(|code| code.to_string());
+                }
+            }
+            if geo.asn.is_none() {
+                if let Ok(asn) = reader.lookup::<geoip2::Asn>(ip) {
+                    geo.asn = asn.autonomous_system_number;
+                }
+            }
+        }
+        geo
+    }
+}
+
+fn load(data: Vec<u8>) -> Result<Reader<Vec<u8>>, String> {
+    Reader::from_source(data).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::matcher::Cidr;
+
+    #[derive(Clone, Copy)]
+    enum Record {
+        Empty,
+        Node(usize),
+        Data(usize),
+    }
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut buf = vec![0x40 | s.len() as u8];
+        buf.extend(s.as_bytes());
+        buf
+    }
+
+    fn uint16(n: u16) -> Vec<u8> {
+        let mut buf = vec![0xa2];
+        buf.extend(n.to_be_bytes());
+        buf
+    }
+
+    fn uint32(n: u32) -> Vec<u8> {
+        let mut buf = vec![0xc4];
+        buf.extend(n.to_be_bytes());
+        buf
+    }
+
+    fn country(code: &str) -> Vec<u8> {
+        [
+            vec![0xe1],
+            string("country"),
+            vec![0xe1],
+            string("iso_code"),
+            string(code),
+        ]
+        .concat()
+    }
+
+    fn asn(n: u32) -> Vec<u8> {
+        [vec![0xe1], string("autonomous_system_number"), uint32(n)].concat()
+    }
+
+    // A minimal IPv4 database with 24-bit records
+    fn mmdb(records: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut nodes = vec![[Record::Empty; 2]];
+        let mut data: Vec<u8> = vec![];
+        for (cidr, record) in records {
+            let cidr = cidr.parse::<Cidr>().unwrap();
+            let ip = match cidr.addr() {
+                IpAddr::V4(v4) => u32::from(v4),
+                IpAddr::V6(_) => unreachable!(),
+            };
+            let mut node = 0;
+            for i in 0..cidr.prefix() {
+                let bit = ((ip >> (31 - i)) & 1) as usize;
+                if i + 1 == cidr.prefix() {
+                    nodes[node][bit] = Record::Data(data.len());
+                    break;
+                }
+                node = match nodes[node][bit] {
+                    Record::Node(next) => next,
+                    _ => {
+                        nodes.push([Record::Empty; 2]);
+                        nodes[node][bit] = Record::Node(nodes.len() - 1);
+                        nodes.len() - 1
+                    }
+                };
+            }
+            data.extend(record);
+        }
+
+        let count = nodes.len();
+        let mut buf = vec![];
+        for record in nodes.iter().flatten() {
+            let value = match record {
+                Record::Empty => count,
+                Record::Node(next) => *next,
+                Record::Data(offset) => count + 16 + offset,
+            };
+            buf.extend(&(value as u32).to_be_bytes()[1..]);
+        }
+        buf.extend([0; 16]);
+        buf.extend(data);
+        buf.extend(b"\xab\xcd\xefMaxMind.com");
+        let metadata = [
+            vec![0xe9],
+            string("binary_format_major_version"),
+            uint16(2),
+            string("binary_format_minor_version"),
+            uint16(0),
+            string("build_epoch"),
+            vec![0x01, 0x02, 0x01],
+            string("database_type"),
+            string("Test"),
+            string("description"),
+            vec![0xe0],
+            string("ip_version"),
+            uint16(4),
+            string("languages"),
+            vec![0x00, 0x04],
+            string("node_count"),
+            uint32(count as u32),
+            string("record_size"),
+            uint16(24),
+        ];
+        buf.extend(metadata.concat());
+        buf
+    }
+
+    #[tokio::test]
+    async fn geoip() {
+        let (country_path, asn_path) = ("./test-geo-country.mmdb", "./test-geo-asn.mmdb");
+        std::fs::write(
+            country_path,
+            mmdb(&[("1.0.0.0/8", country("AU")), ("8.8.8.0/24", country("US"))]),
+        )
+        .unwrap();
+        std::fs::write(asn_path, mmdb(&[("8.8.0.0/16", asn(15169))])).unwrap();
+        let geoip = GeoIp::new(&[country_path, asn_path]);
+        std::fs::remove_file(country_path).unwrap();
+        std::fs::remove_file(asn_path).unwrap();
+        let geoip = geoip.unwrap();
+
+        let lookup = |s: &str| geoip.lookup(s.parse().unwrap());
+        assert_eq!(
+            lookup("8.8.8.8"),
+            Geo {
+                country: Some("US".to_string()),
+                asn: Some(15169),
+            }
+        );
+        assert_eq!(lookup("::ffff:1.2.3.4").country, Some("AU".to_string()));
+        assert_eq!(lookup("1.2.3.4").asn, None);
+        assert_eq!(lookup("8.8.9.9").country, None);
+        assert_eq!(lookup("8.8.9.9").asn, Some(15169));
+        assert_eq!(lookup("2001:db8::1"), Geo::default());
+
+        std::fs::write("./test-geo-bad.mmdb", "not a database").unwrap();
+        assert!(GeoIp::new(&["./test-geo-bad.mmdb"]).is_err());
+        std::fs::remove_file("./test-geo-bad.mmdb").unwrap();
+        assert!(GeoIp::new(&["./test-geo-none.mmdb"]).is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use crate::util::ct_eq;
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use std::collections::HashMap;
+
+const APR1_MAGIC: &str = "$apr1$";
+
+const ITOA64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+// Password hash of an htpasswd entry
+#[derive(Debug, Clone, PartialEq)]
+enum Hash {
+    // $2y$10$...
+    Bcrypt(String),
+    // $5$salt$...
+    Sha256Crypt(String),
+    // $6$salt$...
+    Sha512Crypt(String),
+    // $apr1$salt$...
+    Apr1 { salt: String, hash: String },
+    // {SHA}base64
+    Sha1(Vec<u8>),
+}
+
+impl Hash {
+    fn new(s: &str) -> Result<Self, String> {
+        if ["$2a$", "$2b$", "$2y$"].iter().any(|p| s.starts_with(p)) {
+            Ok(Hash::Bcrypt(s.to_string()))
+        } else if s.starts_with("$5$") {
+            Ok(Hash::Sha256Crypt(s.to_string()))
+        } else if s.starts_with("$6$") {
+            Ok(Hash::Sha512Crypt(s.to_string()))
+        } else if let Some(rest) = s.strip_prefix(APR1_MAGIC) {
+            match rest.split_once('$') {
+                Some((salt, hash)) if hash.len() == 22 => Ok(Hash::Apr1 {
+                    salt: salt.to_string(),
+                    hash: hash.to_string(),
+                }),
+                _ => Err("Wrong APR1 hash".to_string()),
+            }
+        } else if let Some(rest) = s.strip_prefix("{SHA}") {
+            match base64::decode(rest) {
+                Ok(digest) if digest.len() == 20 => Ok(Hash::Sha1(digest)),
+                _ => Err("Wrong SHA1 hash".to_string()),
+            }
+        } else {
+            Err("Unsupported hash, optional value: bcrypt SHA-256 SHA-512 APR1 SHA1".to_string())
+        }
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Hash::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Hash::Sha256Crypt(hash) => sha_crypt::sha256_check(password, hash).is_ok(),
+            Hash::Sha512Crypt(hash) => sha_crypt::sha512_check(password, hash).is_ok(),
+            Hash::Apr1 { salt, hash } => ct_eq(apr1(password, salt).as_bytes(), hash.as_bytes()),
+            Hash::Sha1(digest) => ct_eq(&Sha1::digest(password.as_bytes()), digest),
+        }
+    }
+}
+
+// Users of an htpasswd file
+#[derive(Debug, Clone, Default)]
+pub struct Htpasswd {
+    users: HashMap<String, Hash>,
+    // Checked for unknown users, so they take as long as the others
+    dummy: Option<Hash>,
+}
+
+impl Htpasswd {
+    // One `user:hash` per line, `#` starts a comment
+    pub fn new(s: &str) -> Result<Self, String> {
+        let mut users = HashMap::new();
+        let mut dummy = None;
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = |msg: &str| format!("Line {}: {}", i + 1, msg);
+            let (user, hash) = line
+                .split_once(':')
+                .ok_or_else(|| err("Expected `user:hash`"))?;
+            if user.is_empty() {
+                return Err(err("Empty user name"));
+            }
+            let hash = Hash::new(hash).map_err(|msg| err(&msg))?;
+            dummy.get_or_insert_with(|| hash.clone());
+            users.insert(user.to_string(), hash);
+        }
+        Ok(Self { users, dummy })
+    }
+
+    pub fn load(data: Vec<u8>) -> Result<Self, String> {
+        let s = String::from_utf8(data).map_err(|err| err.to_string())?;
+        Self::new(&s)
+    }
+
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        match self.users.get(user) {
+            Some(hash) => hash.verify(password),
+            None => {
+                if let Some(hash) = &self.dummy {
+                    hash.verify(password);
+                }
+                false
+            }
+        }
+    }
+}
+
+// MD5-crypt with the Apache magic
+// https://svn.apache.org/viewvc/apr/apr-util/branches/1.3.x/crypto/apr_md5.c
+fn apr1(password: &str, salt: &str) -> String {
+    let pw = password.as_bytes();
+    let salt = &salt.as_bytes()[..salt.len().min(8)];
+
+    let alt = Md5::new()
+        .chain_update(pw)
+        .chain_update(salt)
+        .chain_update(pw)
+        .finalize();
+
+    let mut ctx = Md5::new()
+        .chain_update(pw)
+        .chain_update(APR1_MAGIC)
+        .chain_update(salt);
+    for chunk in pw.chunks(16) {
+        ctx.update(&alt[..chunk.len()]);
+    }
+    let mut n = pw.len();
+    while n > 0 {
+        match n & 1 {
+            1 => ctx.update([0]),
+            _ => ctx.update(&pw[..1]),
+        }
+        n >>= 1;
+    }
+    let mut fin = ctx.finalize();
+
+    for i in 0..1000 {
+        let mut ctx = Md5::new();
+        match i & 1 {
+            1 => ctx.update(pw),
+            _ => ctx.update(fin),
+        }
+        if i % 3 != 0 {
+            ctx.update(salt);
+        }
+        if i % 7 != 0 {
+            ctx.update(pw);
+        }
+        match i & 1 {
+            1 => ctx.update(fin),
+            _ => ctx.update(pw),
+        }
+        fin = ctx.finalize();
+    }
+
+    let mut out = String::with_capacity(22);
+    let mut push = |mut v: u32, n: usize| {
+        for _ in 0..n {
+            out.push(ITOA64[(v & 0x3f) as usize] as char);
+            v >>= 6;
+        }
+    };
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        push(
+            (fin[a] as u32) << 16 | (fin[b] as u32) << 8 | fin[c] as u32,
+            4,
+        );
+    }
+    push(fin[11] as u32, 2);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hash() {
+        let cases = [
+            "$apr1$r31sAlt1$aCMop1YrEBcA1RZuMPJnt/",
+            "$5$saltsalt$gOjOtoMpVhru2uyjeJSEc/JaLQWOXMNmlOnj6T4AtC.",
+            "$6$saltsalt$qFmFH.bQmmtXzyBY0s9v7Oicd2z4XSIecDzlB5KiA2/jctKu9YterLp8wwnSq.qc.eoxqOmSuNp2xS0ktL3nh/",
+            "{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=",
+        ];
+        for s in cases {
+            let hash = Hash::new(s).unwrap();
+            assert!(hash.verify("password"), "{}", s);
+            assert!(!hash.verify("Password"), "{}", s);
+        }
+
+        let bcrypt = bcrypt::hash("password", 4).unwrap();
+        let hash = Hash::new(&bcrypt).unwrap();
+        assert!(hash.verify("password"));
+        assert!(!hash.verify("passwor"));
+
+        assert!(Hash::new("password").is_err());
+        assert!(Hash::new("$apr1$salt").is_err());
+        assert!(Hash::new("{SHA}abc").is_err());
+    }
+
+    #[test]
+    fn htpasswd() {
+        let s = "
+            # users
+            a:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=
+            b:$apr1$r31sAlt1$aCMop1YrEBcA1RZuMPJnt/
+        ";
+        let htpasswd = Htpasswd::new(s).unwrap();
+        assert!(htpasswd.verify("a", "password"));
+        assert!(htpasswd.verify("b", "password"));
+        assert!(!htpasswd.verify("b", ""));
+        // Checked against the hash of `a`, but never valid
+        assert!(!htpasswd.verify("c", "password"));
+        assert!(!Htpasswd::default().verify("a", "password"));
+
+        assert_eq!(
+            Htpasswd::new("a:b:c\n").unwrap_err().split(':').next(),
+            Some("Line 1")
+        );
+        assert!(Htpasswd::new("\n\na\n").unwrap_err().starts_with("Line 3:"));
+        assert!(Htpasswd::new(":{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=").is_err());
+    }
+}
@@ -10,6 +10,7 @@ impl Index {
         Self(index)
     }
 
+    #[allow(clippy::wrong_self_convention)]
     pub async fn from_directory(&self, dir: PathBuf) -> Option<(File, String)> {
         for filename in &self.0 {
             let mut path = dir.clone();
@@ -0,0 +1,97 @@
+use crate::error_log::Level;
+use crate::option::syslog::severity;
+use std::path::{Path, PathBuf};
+use tokio::io::Result;
+use tokio::net::UnixDatagram;
+
+pub const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+// Native journal protocol
+// https://systemd.io/JOURNAL_NATIVE_PROTOCOL/
+#[derive(Debug)]
+pub struct Journald {
+    path: PathBuf,
+    tag: String,
+    socket: Option<UnixDatagram>,
+}
+
+impl Journald {
+    pub fn new<P: AsRef<Path>>(path: P, tag: String) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            tag,
+            socket: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn send(&mut self, level: Level, msg: &str) -> Result<()> {
+        let data = self.format(level, msg);
+        if self.socket.is_none() {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(&self.path)?;
+            self.socket = Some(socket);
+        }
+        let rst = match &self.socket {
+            Some(socket) => socket.send(&data).await.map(|_| ()),
+            None => Ok(()),
+        };
+        if rst.is_err() {
+            self.socket = None;
+        }
+        rst
+    }
+
+    fn format(&self, level: Level, msg: &str) -> Vec<u8> {
+        let mut data = vec![];
+        push_field(&mut data, "MESSAGE", msg);
+        push_field(&mut data, "PRIORITY", &severity(level).to_string());
+        push_field(&mut data, "SYSLOG_IDENTIFIER", &self.tag);
+        data
+    }
+}
+
+// KEY=VALUE\n, or KEY\n<64-bit little-endian length>VALUE\n if the value has a newline
+fn push_field(data: &mut Vec<u8>, key: &str, value: &str) {
+    data.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        data.push(b'\n');
+        data.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        data.push(b'=');
+    }
+    data.extend_from_slice(value.as_bytes());
+    data.push(b'\n');
+}
+
+#[test]
+fn test_journald_format() {
+    let journald = Journald::new(JOURNALD_SOCKET, "see".to_string());
+    assert_eq!(
+        journald.format(Level::Error, "a"),
+        b"MESSAGE=a\nPRIORITY=3\nSYSLOG_IDENTIFIER=see\n"
+    );
+
+    let data = journald.format(Level::Info, "a\nb");
+    assert!(data.starts_with(b"MESSAGE\n\x03\0\0\0\0\0\0\0a\nb\nPRIORITY=6\n"));
+}
+
+#[tokio::test]
+async fn test_journald_send() {
+    let path = std::env::temp_dir().join(format!("see-journald-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let server = UnixDatagram::bind(&path).unwrap();
+    let mut journald = Journald::new(&path, "web".to_string());
+    journald.send(Level::Info, "GET /").await.unwrap();
+
+    let mut buf = [0; 1024];
+    let n = server.recv(&mut buf).await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        &buf[..n],
+        b"MESSAGE=GET /\nPRIORITY=6\nSYSLOG_IDENTIFIER=web\n"
+    );
+}
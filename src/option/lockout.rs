@@ -0,0 +1,230 @@
+use crate::config::default;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Client {
+    Ip(IpAddr),
+    User(String),
+}
+
+#[derive(Debug)]
+struct Entry {
+    failures: u32,
+    // Lockouts so far, doubles the next one
+    level: u32,
+    until: Option<Instant>,
+    last: Instant,
+}
+
+// Lock out clients and user names after failed attempts
+#[derive(Debug, Clone)]
+pub struct Lockout {
+    attempts: u32,
+    time: Duration,
+    // Longest lockout, also forget the failures after it
+    max: Duration,
+    entries: Arc<Mutex<HashMap<Client, Entry>>>,
+}
+
+impl Default for Lockout {
+    fn default() -> Self {
+        Self {
+            attempts: default::LOCKOUT_ATTEMPTS,
+            time: default::LOCKOUT_TIME,
+            max: default::LOCKOUT_MAX,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl Lockout {
+    pub fn attempts(mut self, attempts: u32) -> Result<Self, String> {
+        if attempts == 0 {
+            return Err("`attempts` must be greater than 0".to_string());
+        }
+        self.attempts = attempts;
+        Ok(self)
+    }
+
+    pub fn time(mut self, time: Duration) -> Self {
+        self.time = time;
+        self
+    }
+
+    pub fn max(mut self, max: Duration) -> Self {
+        self.max = max;
+        self
+    }
+
+    // Time left when the client is locked out
+    pub fn check(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let entries = self.entries.lock().unwrap();
+        match entries
+            .get(&Client::Ip(ip))
+            .and_then(|entry| left(entry, now))
+        {
+            Some(left) => Err(left),
+            None => Ok(()),
+        }
+    }
+
+    // Time of the new lockout, if the failure starts one
+    // A locked user name only locks out the clients that fail on it,
+    // so the owner can still log in from elsewhere
+    pub fn fail(&self, ip: IpAddr, user: Option<&str>, now: Instant) -> Option<Duration> {
+        let mut entries = self.entries.lock().unwrap();
+        let user_left = user.and_then(|user| {
+            let client = Client::User(user.to_string());
+            self.count(&mut entries, client.clone(), now);
+            left(entries.get(&client)?, now)
+        });
+
+        let mut locked = self.count(&mut entries, Client::Ip(ip), now);
+        if let (Some(user_left), Some(entry)) = (user_left, entries.get_mut(&Client::Ip(ip))) {
+            if left(entry, now) < Some(user_left) {
+                entry.until = Some(now + user_left);
+                locked = locked.max(Some(user_left));
+            }
+        }
+        locked
+    }
+
+    fn count(
+        &self,
+        entries: &mut HashMap<Client, Entry>,
+        client: Client,
+        now: Instant,
+    ) -> Option<Duration> {
+        if entries.len() >= default::LOCKOUT_ENTRIES && !entries.contains_key(&client) {
+            evict(entries);
+        }
+        let entry = entries.entry(client).or_insert(Entry {
+            failures: 0,
+            level: 0,
+            until: None,
+            last: now,
+        });
+        // Start over after a quiet period
+        let unlocked = entry.until.map(|until| until <= now).unwrap_or(true);
+        if unlocked && now.duration_since(entry.last) >= self.max {
+            entry.failures = 0;
+            entry.level = 0;
+        }
+        entry.failures += 1;
+        entry.last = now;
+
+        if entry.failures < self.attempts {
+            return None;
+        }
+        let time = self
+            .time
+            .checked_mul(2u32.saturating_pow(entry.level))
+            .unwrap_or(self.max)
+            .min(self.max);
+        entry.until = Some(now + time);
+        entry.level += 1;
+        entry.failures = 0;
+        Some(time)
+    }
+
+    // Reset the counters of the client and the user name
+    pub fn success(&self, ip: IpAddr, user: Option<&str>) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(&Client::Ip(ip));
+        if let Some(user) = user {
+            entries.remove(&Client::User(user.to_string()));
+        }
+    }
+}
+
+fn left(entry: &Entry, now: Instant) -> Option<Duration> {
+    entry
+        .until?
+        .checked_duration_since(now)
+        .filter(|left| !left.is_zero())
+}
+
+// Remove the quarter of the entries that were used longest ago
+fn evict(entries: &mut HashMap<Client, Entry>) {
+    let mut oldest = entries
+        .iter()
+        .map(|(client, entry)| (entry.last, client.clone()))
+        .collect::<Vec<_>>();
+    oldest.sort_unstable_by_key(|(last, _)| *last);
+    for (_, client) in oldest.into_iter().take(entries.len() / 4) {
+        entries.remove(&client);
+    }
+}
+
+#[test]
+fn test_lockout() {
+    let lockout = Lockout::default()
+        .attempts(3)
+        .unwrap()
+        .time(Duration::from_secs(10))
+        .max(Duration::from_secs(30));
+    let (ip, other) = ("127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap());
+    let now = Instant::now();
+    let at = |secs: u64| now + Duration::from_secs(secs);
+
+    assert_eq!(lockout.fail(ip, Some("tom"), now), None);
+    assert_eq!(lockout.fail(ip, Some("tom"), now), None);
+    assert_eq!(
+        lockout.fail(ip, Some("tom"), now),
+        Some(Duration::from_secs(10))
+    );
+    assert_eq!(lockout.check(ip, at(4)), Err(Duration::from_secs(6)));
+    // Other clients can still try the user, a failure locks them out at once
+    assert!(lockout.check(other, at(4)).is_ok());
+    assert_eq!(
+        lockout.fail(other, Some("tom"), at(4)),
+        Some(Duration::from_secs(6))
+    );
+    assert!(lockout.check(ip, at(10)).is_ok());
+
+    // Doubled up to `max`
+    for _ in 0..2 {
+        lockout.fail(ip, None, at(10));
+    }
+    assert_eq!(
+        lockout.fail(ip, None, at(10)),
+        Some(Duration::from_secs(20))
+    );
+    for _ in 0..2 {
+        lockout.fail(ip, None, at(30));
+    }
+    assert_eq!(
+        lockout.fail(ip, None, at(30)),
+        Some(Duration::from_secs(30))
+    );
+
+    // A successful login resets the client and the user
+    lockout.success(other, Some("tom"));
+    assert!(lockout.check(ip, at(31)).is_err());
+    assert_eq!(lockout.fail(other, Some("tom"), at(31)), None);
+    lockout.fail(ip, None, at(61));
+    lockout.success(ip, None);
+    for _ in 0..2 {
+        assert_eq!(lockout.fail(ip, None, at(61)), None);
+    }
+    assert!(Lockout::default().attempts(0).is_err());
+}
+
+#[test]
+fn test_lockout_entries() {
+    let lockout = Lockout::default();
+    let now = Instant::now();
+    for i in 0..=default::LOCKOUT_ENTRIES as u32 {
+        let ip = IpAddr::from(std::net::Ipv4Addr::from(i));
+        lockout.fail(ip, None, now + Duration::from_millis(i as u64));
+    }
+    let entries = lockout.entries.lock().unwrap();
+    assert_eq!(
+        entries.len(),
+        default::LOCKOUT_ENTRIES - default::LOCKOUT_ENTRIES / 4 + 1
+    );
+    assert!(!entries.contains_key(&Client::Ip("0.0.0.0".parse().unwrap())));
+}
@@ -1,68 +1,256 @@
-use crate::config::Var;
-use hyper::{Body, Request};
+use crate::config::{Context, Var};
+use crate::error_log::Level;
+use crate::option::{LogBuffer, LogFile, LogFilter, LogOutput, LogWriter, Rotate, UpstreamTime};
+use futures_util::ready;
+use hyper::body::{Bytes, HttpBody, SizeHint};
+use hyper::{Body, HeaderMap, Request, Response};
+use std::future::Future;
 use std::path::Path;
-use std::sync::Arc;
-use tokio::fs::{File, OpenOptions};
-use tokio::io::{self, AsyncWriteExt, Result, Stdout};
-use tokio::sync::Mutex;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context as TaskContext, Poll};
+use tokio::io::Result;
 
 #[derive(Clone, Debug)]
 pub struct Logger {
     format: Var<String>,
-    file: Option<Arc<Mutex<File>>>,
-    stdout: Option<Arc<Mutex<Stdout>>>,
+    filter: LogFilter,
+    buffer: LogBuffer,
+    writer: Option<LogWriter>,
 }
 
 impl Logger {
-    pub fn new<S: AsRef<str>>(format: S) -> Self {
+    pub fn new<S: AsRef<str>>(format: S) -> std::result::Result<Self, String> {
         let mut f = format.as_ref().to_string();
         f += "\n";
 
-        Self {
-            format: Var::from(f),
-            file: None,
-            stdout: None,
-        }
+        Ok(Self {
+            format: Var::from_str(&f)?,
+            filter: LogFilter::default(),
+            buffer: LogBuffer::default(),
+            writer: None,
+        })
     }
 
-    // Set output to file
-    pub async fn file<P: AsRef<Path>>(mut self, path: P) -> Result<Self> {
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(path)
-            .await
-            .map(|file| Arc::new(Mutex::new(file)))?;
-        self.file = Some(file);
+    // Skip or sample entries
+    pub fn filter(mut self, filter: LogFilter) -> Self {
+        self.filter = filter;
 
-        Ok(self)
+        self
     }
 
-    // Set output to terminal stdout
-    pub fn stdout(mut self) -> Self {
-        self.stdout = Some(Arc::new(Mutex::new(io::stdout())));
+    // Buffer of the writer, must be set before the output
+    pub fn buffer(mut self, buffer: LogBuffer) -> Self {
+        self.buffer = buffer;
+
+        self
+    }
+
+    pub fn output(mut self, output: LogOutput) -> Self {
+        self.writer = Some(LogWriter::spawn(output, &self.buffer));
 
         self
     }
 
-    pub async fn write(&mut self, req: &Request<Body>) {
-        let text = self.format.clone().map(|s, r| r.replace(s, req));
-        let bytes = text.as_bytes();
+    // Set output to file, optionally rotated
+    pub async fn file<P: AsRef<Path>>(self, path: P, rotate: Option<Rotate>) -> Result<Self> {
+        let file = LogFile::open(path, rotate).await?;
+
+        Ok(self.output(LogOutput::File(file)))
+    }
+
+    // Set output to terminal stdout
+    pub fn stdout(self) -> Self {
+        self.output(LogOutput::stdout())
+    }
+
+    // Build a format that writes one JSON object per line
+    // Each field is `key=variable` or `variable`, the key defaults to the variable name
+    pub fn json_format<S: AsRef<str>>(fields: &[S]) -> std::result::Result<String, String> {
+        if fields.is_empty() {
+            return Err("JSON log fields cannot be empty".to_string());
+        }
+
+        let mut format = String::from("{");
+        for (i, field) in fields.iter().enumerate() {
+            let field = field.as_ref();
+            let (key, var) = match field.split_once('=') {
+                Some((key, var)) => (key, var),
+                None => (field.split('|').next().unwrap_or_default(), field),
+            };
+            if key.is_empty() || var.is_empty() {
+                return Err(format!("Invalid JSON log field `{}`", field));
+            }
+            if !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+            {
+                return Err(format!("Invalid JSON log field name `{}`", key));
+            }
+            if i > 0 {
+                format.push(',');
+            }
+            format.push_str(&format!("\"{}\":\"$`{}|json`\"", key, var));
+        }
+        format.push('}');
+
+        Ok(format)
+    }
+
+    pub fn render(&self, req: &Request<Body>, ctx: &Context) -> String {
+        self.format.render(req, ctx)
+    }
+
+    pub async fn write(&self, text: String) {
+        if let Some(writer) = &self.writer {
+            writer.write(Level::Info, text).await;
+        }
+    }
+
+    pub async fn flush(&self) {
+        if let Some(writer) = &self.writer {
+            writer.flush().await;
+        }
+    }
+
+    // Write the log when the response body is finished or aborted
+    pub fn wrap(
+        self,
+        res: Response<Body>,
+        req: Request<Body>,
+        mut ctx: Context,
+    ) -> Response<LogBody> {
+        ctx.response.status = Some(res.status());
+        ctx.response.upstream_time = res.extensions().get::<UpstreamTime>().map(|t| t.0);
+
+        res.map(|body| LogBody {
+            body,
+            record: Some(Record {
+                logger: self,
+                req,
+                ctx,
+            }),
+            writing: None,
+        })
+    }
+}
+
+// Copy of the request line and headers, the body is not needed
+pub fn request_head(req: &Request<Body>) -> Request<Body> {
+    let mut head = Request::new(Body::empty());
+    *head.method_mut() = req.method().clone();
+    *head.uri_mut() = req.uri().clone();
+    *head.version_mut() = req.version();
+    *head.headers_mut() = req.headers().clone();
+    head
+}
+
+struct Record {
+    logger: Logger,
+    req: Request<Body>,
+    ctx: Context,
+}
+
+impl Record {
+    fn line(&self) -> Option<String> {
+        match self.logger.filter.is_logged(&self.req, &self.ctx) {
+            true => Some(self.logger.render(&self.req, &self.ctx)),
+            false => None,
+        }
+    }
+}
+
+// Count the bytes of the response body, the size hint is kept for `content-length`
+// With `overflow block` the body ends after the line is queued,
+// so a full queue holds back the connection
+pub struct LogBody {
+    body: Body,
+    record: Option<Record>,
+    writing: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+impl From<Body> for LogBody {
+    // Nothing to log
+    fn from(body: Body) -> Self {
+        Self {
+            body,
+            record: None,
+            writing: None,
+        }
+    }
+}
 
-        // file
-        if let Some(file) = &self.file {
-            let arc = file.clone();
-            let mut file = arc.lock().await;
+impl LogBody {
+    fn is_blocking(&self) -> bool {
+        match &self.record {
+            Some(record) => matches!(&record.logger.writer, Some(writer) if writer.is_blocking()),
+            None => false,
+        }
+    }
+}
+
+impl HttpBody for LogBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<std::result::Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+        if let Some(writing) = &mut this.writing {
+            ready!(writing.as_mut().poll(cx));
+            this.writing = None;
+            return Poll::Ready(None);
+        }
+
+        let poll = Pin::new(&mut this.body).poll_data(cx);
+        let blocking = this.is_blocking();
+        match (&poll, &mut this.record) {
+            (Poll::Ready(Some(Ok(data))), Some(record)) => {
+                record.ctx.response.body_bytes_sent += data.len() as u64;
+            }
+            (Poll::Ready(None), Some(_)) if blocking => {
+                let record = this.record.take().unwrap();
+                if let (Some(line), Some(writer)) = (record.line(), record.logger.writer) {
+                    this.writing = Some(Box::pin(async move {
+                        writer.write(Level::Info, line).await;
+                    }));
+                    return Pin::new(this).poll_data(cx);
+                }
+            }
+            _ => {}
+        }
+        poll
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::result::Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.get_mut().body).poll_trailers(cx)
+    }
 
-            let _ = file.write(bytes).await;
+    fn is_end_stream(&self) -> bool {
+        // The log line is still to be queued
+        if self.writing.is_some() || self.is_blocking() {
+            return false;
         }
+        self.body.is_end_stream()
+    }
 
-        // stdout
-        if let Some(stdout) = &self.stdout {
-            let arc = stdout.clone();
-            let mut stdout = arc.lock().await;
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
 
-            let _ = stdout.write(bytes).await;
+// The response was aborted or the writer does not block
+impl Drop for LogBody {
+    fn drop(&mut self) {
+        if let Some(record) = self.record.take() {
+            if let (Some(line), Some(writer)) = (record.line(), &record.logger.writer) {
+                writer.send(Level::Info, line);
+            }
         }
     }
 }
@@ -76,9 +264,10 @@ async fn test_logger_file() {
     let file = "./test.log";
     let data = "12345";
 
-    let mut logger = Logger::new(&data).file(file).await.unwrap();
+    let logger = Logger::new(data).unwrap().file(file, None).await.unwrap();
     let req = Request::new(Body::empty());
-    logger.write(&req).await;
+    logger.write(logger.render(&req, &Context::default())).await;
+    logger.flush().await;
 
     let content = fs::read_to_string(file).await.unwrap();
     fs::remove_file(file).await.unwrap();
@@ -88,3 +277,69 @@ async fn test_logger_file() {
 
 #[tokio::test]
 async fn test_logger_stdout() {}
+
+#[tokio::test]
+async fn test_logger_response() {
+    use hyper::StatusCode;
+    use std::time::Duration;
+    use tokio::fs;
+
+    let file = "./test-response.log";
+    let logger = Logger::new("$`status` $`body_bytes_sent` $`upstream_response_time|default:-`")
+        .unwrap()
+        .file(file, None)
+        .await
+        .unwrap();
+
+    let mut res = Response::new(Body::from("hello"));
+    *res.status_mut() = StatusCode::NOT_FOUND;
+    res.extensions_mut()
+        .insert(UpstreamTime(Duration::from_millis(1500)));
+    let res = logger
+        .clone()
+        .wrap(res, Request::new(Body::empty()), Context::default());
+    assert_eq!(res.body().size_hint().exact(), Some(5));
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    assert_eq!(body, "hello");
+
+    logger.flush().await;
+    let content = fs::read_to_string(file).await.unwrap();
+    fs::remove_file(file).await.unwrap();
+
+    assert_eq!(content, "404 5 1.500\n");
+}
+
+#[test]
+fn test_logger_format() {
+    use crate::config::default;
+    use crate::server::Connection;
+
+    let req = Request::builder()
+        .uri("/a?b=1")
+        .header("host", "example.com")
+        .header("user-agent", "curl \"7\"\t")
+        .body(Body::empty())
+        .unwrap();
+    let mut ctx = Context::new(Connection::default());
+    ctx.response.status = Some(hyper::StatusCode::OK);
+    ctx.response.body_bytes_sent = 12;
+
+    let format = Logger::json_format(&["status", "ua=header_user-agent", "path|upper"]).unwrap();
+    let logger = Logger::new(format).unwrap();
+    assert_eq!(
+        logger.render(&req, &ctx),
+        "{\"status\":\"200\",\"ua\":\"curl \\\"7\\\"\\t\",\"path\":\"/A\"}\n"
+    );
+    assert!(Logger::json_format::<&str>(&[]).is_err());
+    assert!(Logger::json_format(&["=path"]).is_err());
+    assert!(Logger::json_format(&["a\"b=path"]).is_err());
+    assert!(Logger::new(Logger::json_format(&default::LOG_JSON_FIELDS).unwrap()).is_ok());
+
+    let logger = Logger::new(default::LOG_FORMAT_COMBINED).unwrap();
+    let line = logger.render(&req, &ctx);
+    assert!(line.starts_with("0.0.0.0 - - ["));
+    assert!(line.ends_with("] \"GET /a?b=1 HTTP/1.1\" 200 12 \"-\" \"curl \\x227\\x22\\x09\"\n"));
+
+    let logger = Logger::new(default::LOG_FORMAT_COMMON).unwrap();
+    assert!(logger.render(&req, &ctx).ends_with("\" 200 12\n"));
+}
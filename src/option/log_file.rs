@@ -0,0 +1,376 @@
+use crate::{log_error, util};
+use async_compression::tokio::write::GzipEncoder;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use time::{Date, OffsetDateTime};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{self, AsyncWriteExt, Result};
+use tokio::sync::Mutex as AsyncMutex;
+
+// Incremented to ask every log file to reopen before the next write
+static REOPEN: AtomicUsize = AtomicUsize::new(0);
+
+// Reopen all log files, e.g. after they were moved by logrotate
+pub fn reopen_all() {
+    REOPEN.fetch_add(1, Ordering::SeqCst);
+}
+
+// Listen for `SIGUSR1` and reopen all log files
+#[cfg(unix)]
+pub async fn reopen_on_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sig = match signal(SignalKind::user_defined1()) {
+        Ok(sig) => sig,
+        Err(_) => return,
+    };
+    while sig.recv().await.is_some() {
+        reopen_all();
+    }
+}
+
+// log {
+//     rotate {
+//         size 100M
+//         daily on
+//         keep 7
+//         compress on
+//     }
+// }
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Rotate {
+    pub size: Option<u64>,
+    pub daily: bool,
+    // Number of rotated files to keep, 0 keeps all
+    pub keep: usize,
+    pub compress: bool,
+}
+
+lazy_static! {
+    // Open log files by canonical path, all sites logging to a path share one
+    static ref FILES: Mutex<HashMap<PathBuf, Weak<Shared>>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug)]
+pub struct LogFile {
+    path: PathBuf,
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    rotate: Option<Rotate>,
+    state: AsyncMutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    // Local date of the first line in the current file
+    date: Date,
+    reopen: usize,
+}
+
+impl LogFile {
+    // Open `path` for appending, or share the file if it is already open
+    pub async fn open<P: AsRef<Path>>(path: P, rotate: Option<Rotate>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let (file, size, date) = open_append(&path).await?;
+        let canonical = fs::canonicalize(&path).await?;
+
+        let mut files = FILES.lock().unwrap();
+        files.retain(|_, shared| shared.strong_count() > 0);
+        if let Some(shared) = files.get(&canonical).and_then(Weak::upgrade) {
+            if shared.rotate != rotate {
+                return Err(io::Error::other(
+                    "The file is already used with different rotate settings",
+                ));
+            }
+            return Ok(Self { path, shared });
+        }
+
+        let shared = Arc::new(Shared {
+            rotate,
+            state: AsyncMutex::new(State {
+                path: canonical.clone(),
+                file,
+                size,
+                date,
+                reopen: REOPEN.load(Ordering::SeqCst),
+            }),
+        });
+        files.insert(canonical, Arc::downgrade(&shared));
+
+        Ok(Self { path, shared })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn write(&self, bytes: &[u8]) -> Result<()> {
+        let mut state = self.shared.state.lock().await;
+        state.write(bytes, &self.shared.rotate).await
+    }
+}
+
+impl State {
+    async fn write(&mut self, bytes: &[u8], rotate: &Option<Rotate>) -> Result<()> {
+        let reopen = REOPEN.load(Ordering::SeqCst);
+        if reopen != self.reopen {
+            self.reopen = reopen;
+            self.reopen().await?;
+        }
+
+        if let Some(rotate) = rotate {
+            if self.should_rotate(rotate, bytes.len() as u64) {
+                self.rotate(rotate.clone()).await?;
+            }
+        }
+
+        self.file.write_all(bytes).await?;
+        self.size += bytes.len() as u64;
+
+        // tokio writes to the file in the background until flushed
+        self.file.flush().await
+    }
+
+    async fn reopen(&mut self) -> Result<()> {
+        let (file, size, date) = open_append(&self.path).await?;
+        self.file = file;
+        self.size = size;
+        self.date = date;
+
+        Ok(())
+    }
+
+    fn should_rotate(&self, rotate: &Rotate, len: u64) -> bool {
+        // Never rotate an empty file
+        if self.size == 0 {
+            return false;
+        }
+        if let Some(size) = rotate.size {
+            if self.size + len > size {
+                return true;
+            }
+        }
+        rotate.daily && util::now_local().date() != self.date
+    }
+
+    // Move the current file to `name.YYYYmmdd-HHMMSS` and open a new one
+    async fn rotate(&mut self, rotate: Rotate) -> Result<()> {
+        let _ = self.file.flush().await;
+        let suffix = util::now_local().format("%Y%m%d-%H%M%S");
+        let mut rotated = PathBuf::from(format!("{}.{}", self.path.display(), suffix));
+        let mut n = 1;
+        while fs::metadata(&rotated).await.is_ok() || fs::metadata(gz_path(&rotated)).await.is_ok()
+        {
+            rotated = PathBuf::from(format!("{}.{}-{}", self.path.display(), suffix, n));
+            n += 1;
+        }
+        fs::rename(&self.path, &rotated).await?;
+        self.reopen().await?;
+
+        // Compress and clean up in the background
+        let path = self.path.clone();
+        tokio::spawn(async move {
+            if rotate.compress {
+                if let Err(err) = compress(&rotated).await {
+                    log_error!("Compress log '{}' failed: {}", rotated.display(), err);
+                }
+            }
+            if rotate.keep > 0 {
+                if let Err(err) = remove_old(&path, rotate.keep).await {
+                    log_error!("Remove old logs of '{}' failed: {}", path.display(), err);
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+async fn open_append(path: &Path) -> Result<(File, u64, Date)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    let meta = file.metadata().await?;
+    let date = match meta.modified() {
+        Ok(time) if meta.len() > 0 => {
+            let dt = OffsetDateTime::from(time);
+            dt.to_offset(util::now_local().offset()).date()
+        }
+        _ => util::now_local().date(),
+    };
+
+    Ok((file, meta.len(), date))
+}
+
+fn gz_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.gz", path.display()))
+}
+
+async fn compress(path: &Path) -> Result<()> {
+    let gz = gz_path(path);
+    let mut src = File::open(path).await?;
+    let mut encoder = GzipEncoder::new(File::create(&gz).await?);
+    io::copy(&mut src, &mut encoder).await?;
+    encoder.shutdown().await?;
+    fs::remove_file(path).await
+}
+
+// Keep the newest `keep` rotated files of `path`
+async fn remove_old(path: &Path, keep: usize) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let prefix = match path.file_name() {
+        Some(name) => format!("{}.", name.to_string_lossy()),
+        None => return Ok(()),
+    };
+
+    let mut rotated = vec![];
+    let mut entries = fs::read_dir(&dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some(suffix) = name.strip_prefix(&prefix) {
+            let suffix = suffix.trim_end_matches(".gz");
+            if is_rotated_suffix(suffix) {
+                rotated.push((suffix.to_string(), entry.path()));
+            }
+        }
+    }
+
+    // The suffix is a timestamp, sorted from new to old
+    rotated.sort_by(|a, b| b.0.cmp(&a.0));
+    for (_, path) in rotated.into_iter().skip(keep) {
+        fs::remove_file(path).await?;
+    }
+
+    Ok(())
+}
+
+// YYYYmmdd-HHMMSS or YYYYmmdd-HHMMSS-N
+fn is_rotated_suffix(s: &str) -> bool {
+    let b = s.as_bytes();
+    b.len() >= 15
+        && b[..8].iter().all(u8::is_ascii_digit)
+        && b[8] == b'-'
+        && b[9..15].iter().all(u8::is_ascii_digit)
+        && (b.len() == 15
+            || (b[15] == b'-' && b.len() > 16 && b[16..].iter().all(u8::is_ascii_digit)))
+}
+
+#[tokio::test]
+async fn test_log_file_rotate() {
+    let dir = PathBuf::from("./test-rotate");
+    let _ = fs::remove_dir_all(&dir).await;
+    fs::create_dir(&dir).await.unwrap();
+    let path = dir.join("www.log");
+
+    let rotate = Rotate {
+        size: Some(10),
+        keep: 2,
+        ..Default::default()
+    };
+    let file = LogFile::open(&path, Some(rotate)).await.unwrap();
+    for _ in 0..4 {
+        file.write(b"12345678\n").await.unwrap();
+    }
+    assert_eq!(fs::read_to_string(&path).await.unwrap(), "12345678\n");
+
+    // Wait for the background cleanup
+    let mut count = 0;
+    for _ in 0..50 {
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        let mut entries = fs::read_dir(&dir).await.unwrap();
+        count = 0;
+        while entries.next_entry().await.unwrap().is_some() {
+            count += 1;
+        }
+        if count == 3 {
+            break;
+        }
+    }
+    fs::remove_dir_all(&dir).await.unwrap();
+    assert_eq!(count, 3);
+}
+
+#[tokio::test]
+async fn test_log_file_reopen() {
+    let path = PathBuf::from("./test-reopen.log");
+    let moved = PathBuf::from("./test-reopen.log.1");
+    let file = LogFile::open(&path, None).await.unwrap();
+    file.write(b"a\n").await.unwrap();
+
+    fs::rename(&path, &moved).await.unwrap();
+    reopen_all();
+    file.write(b"b\n").await.unwrap();
+
+    let old = fs::read_to_string(&moved).await.unwrap();
+    let new = fs::read_to_string(&path).await.unwrap();
+    fs::remove_file(&path).await.unwrap();
+    fs::remove_file(&moved).await.unwrap();
+    assert_eq!((old.as_str(), new.as_str()), ("a\n", "b\n"));
+}
+
+#[test]
+fn test_is_rotated_suffix() {
+    assert!(is_rotated_suffix("20261018-214419"));
+    assert!(is_rotated_suffix("20261018-214419-2"));
+    assert!(!is_rotated_suffix("20261018-214419-"));
+    assert!(!is_rotated_suffix("20261018"));
+    assert!(!is_rotated_suffix("bak"));
+}
+
+#[tokio::test]
+async fn test_log_file_compress() {
+    let path = PathBuf::from("./test-compress.log");
+    fs::write(&path, "a\n").await.unwrap();
+    compress(&path).await.unwrap();
+
+    let gz = gz_path(&path);
+    let data = fs::read(&gz).await.unwrap();
+    fs::remove_file(&gz).await.unwrap();
+    assert!(fs::metadata(&path).await.is_err());
+    assert_eq!(&data[..2], &[0x1f, 0x8b]);
+}
+
+#[tokio::test]
+async fn test_log_file_shared() {
+    let dir = PathBuf::from("./test-shared");
+    let _ = fs::remove_dir_all(&dir).await;
+    fs::create_dir(&dir).await.unwrap();
+    let path = dir.join("www.log");
+
+    let rotate = Rotate {
+        size: Some(10),
+        ..Default::default()
+    };
+    let a = LogFile::open(&path, Some(rotate.clone())).await.unwrap();
+    let b = LogFile::open(dir.join("../test-shared/www.log"), Some(rotate))
+        .await
+        .unwrap();
+    let other = LogFile::open(&path, None).await;
+    a.write(b"12345678\n").await.unwrap();
+    b.write(b"12345678\n").await.unwrap();
+
+    // Rotated once by the shared file, the second line is in the new file
+    let content = fs::read_to_string(&path).await.unwrap();
+    let mut count = 0;
+    let mut entries = fs::read_dir(&dir).await.unwrap();
+    while entries.next_entry().await.unwrap().is_some() {
+        count += 1;
+    }
+    fs::remove_dir_all(&dir).await.unwrap();
+    assert!(other.is_err());
+    assert_eq!((content.as_str(), count), ("12345678\n", 2));
+}
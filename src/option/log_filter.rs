@@ -0,0 +1,220 @@
+use crate::config::{Context, Var};
+use crate::matcher::LocationMatcher;
+use hyper::{Body, Method, Request, StatusCode};
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+
+// 404 or 4xx
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatusPattern {
+    Code(u16),
+    Class(u16),
+}
+
+impl StatusPattern {
+    pub fn new(s: &str) -> Result<Self, String> {
+        let err = || format!("Cannot parse `{}` to status, e.g. `404` `4xx`", s);
+        let b = s.as_bytes();
+        if b.len() != 3 || !(b'1'..=b'5').contains(&b[0]) {
+            return Err(err());
+        }
+        if &s[1..] == "xx" {
+            return Ok(StatusPattern::Class((b[0] - b'0') as u16));
+        }
+        s.parse::<u16>().map(StatusPattern::Code).map_err(|_| err())
+    }
+
+    pub fn is_match(&self, status: StatusCode) -> bool {
+        match self {
+            StatusPattern::Code(code) => status.as_u16() == *code,
+            StatusPattern::Class(class) => status.as_u16() / 100 == *class,
+        }
+    }
+}
+
+// Value of a variable
+#[derive(Debug, Clone)]
+pub enum ValuePattern {
+    // Not empty and not `0`
+    True,
+    Exact(String),
+    Regex(Regex),
+}
+
+impl ValuePattern {
+    fn is_match(&self, value: &str) -> bool {
+        match self {
+            ValuePattern::True => !value.is_empty() && value != "0",
+            ValuePattern::Exact(s) => value == s,
+            ValuePattern::Regex(reg) => reg.is_match(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Condition {
+    Status(Vec<StatusPattern>),
+    Path(LocationMatcher),
+    Method(Vec<Method>),
+    Var(Var<String>, ValuePattern),
+}
+
+impl Condition {
+    fn is_match(&self, req: &Request<Body>, ctx: &Context) -> bool {
+        match self {
+            Condition::Status(patterns) => match ctx.response.status {
+                Some(status) => patterns.iter().any(|p| p.is_match(status)),
+                None => false,
+            },
+            Condition::Path(matcher) => {
+                let path = percent_decode_str(req.uri().path()).decode_utf8_lossy();
+                matcher.is_match(&path)
+            }
+            Condition::Method(methods) => methods.contains(req.method()),
+            Condition::Var(var, pattern) => pattern.is_match(&var.render(req, ctx)),
+        }
+    }
+}
+
+// log {
+//     skip status 3xx
+//     skip path ^ /health
+//     sample 2xx 1%
+// }
+#[derive(Debug, Clone, Default)]
+pub struct LogFilter {
+    // Skip the entry if any condition matches
+    skip: Vec<Condition>,
+    // The first matching status decides the rate, others are always logged
+    sample: Vec<(StatusPattern, f64)>,
+}
+
+impl LogFilter {
+    pub fn skip(&mut self, condition: Condition) {
+        self.skip.push(condition);
+    }
+
+    pub fn sample(&mut self, status: StatusPattern, rate: f64) {
+        self.sample.push((status, rate));
+    }
+
+    pub fn is_logged(&self, req: &Request<Body>, ctx: &Context) -> bool {
+        if self.skip.iter().any(|c| c.is_match(req, ctx)) {
+            return false;
+        }
+
+        let rate = ctx.response.status.and_then(|status| {
+            self.sample
+                .iter()
+                .find(|(p, _)| p.is_match(status))
+                .map(|(_, rate)| *rate)
+        });
+        match rate {
+            Some(rate) => sample_value(&ctx.request_id) < rate,
+            None => true,
+        }
+    }
+}
+
+// 1% or 0.01
+pub fn to_rate(s: &str) -> Result<f64, String> {
+    let rate = match s.strip_suffix('%') {
+        Some(n) => n.parse::<f64>().map(|n| n / 100.0),
+        None => s.parse::<f64>(),
+    };
+    match rate {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err(format!("Cannot parse `{}` to rate, e.g. `1%` `0.01`", s)),
+    }
+}
+
+// The random request id gives a number in [0, 1)
+fn sample_value(request_id: &str) -> f64 {
+    let n = request_id
+        .get(..13)
+        .and_then(|s| u64::from_str_radix(s, 16).ok())
+        .unwrap_or_default();
+    n as f64 / (1u64 << 52) as f64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    fn ctx(status: u16, request_id: &str) -> Context {
+        let mut ctx = Context::default();
+        ctx.response.status = Some(StatusCode::from_u16(status).unwrap());
+        ctx.request_id = request_id.to_string();
+        ctx
+    }
+
+    #[test]
+    fn status() {
+        assert_eq!(StatusPattern::new("404").unwrap(), StatusPattern::Code(404));
+        assert_eq!(StatusPattern::new("2xx").unwrap(), StatusPattern::Class(2));
+        assert!(StatusPattern::new("6xx").is_err());
+        assert!(StatusPattern::new("20").is_err());
+        assert!(StatusPattern::new("2x0").is_err());
+        assert!(StatusPattern::Class(3).is_match(StatusCode::NOT_MODIFIED));
+    }
+
+    #[test]
+    fn rate() {
+        assert_eq!(to_rate("1%").unwrap(), 0.01);
+        assert_eq!(to_rate("0.5").unwrap(), 0.5);
+        assert!(to_rate("200%").is_err());
+        assert!(to_rate("a").is_err());
+        assert_eq!(sample_value("0000000000000000"), 0.0);
+        assert!(sample_value("ffffffffffffffff") < 1.0);
+    }
+
+    #[test]
+    fn skip() {
+        let mut filter = LogFilter::default();
+        filter.skip(Condition::Status(vec![StatusPattern::Class(3)]));
+        filter.skip(Condition::Path(LocationMatcher::start("/health")));
+        filter.skip(Condition::Method(vec![Method::OPTIONS]));
+        filter.skip(Condition::Var(
+            Var::from_str("$`header_user-agent`").unwrap(),
+            ValuePattern::Regex(Regex::new("^kube-probe").unwrap()),
+        ));
+
+        let req = |method: &str, path: &str, ua: &str| {
+            Request::builder()
+                .method(method)
+                .uri(path)
+                .header("user-agent", ua)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let ok = ctx(200, "");
+        assert!(filter.is_logged(&req("GET", "/a", "curl"), &ok));
+        assert!(!filter.is_logged(&req("GET", "/a", "curl"), &ctx(304, "")));
+        assert!(!filter.is_logged(&req("GET", "/health%2Fz", "curl"), &ok));
+        assert!(!filter.is_logged(&req("OPTIONS", "/a", "curl"), &ok));
+        assert!(!filter.is_logged(&req("GET", "/a", "kube-probe/1.2"), &ok));
+    }
+
+    #[test]
+    fn sample() {
+        let mut filter = LogFilter::default();
+        filter.sample(StatusPattern::Class(2), 0.5);
+        filter.sample(StatusPattern::Code(404), 0.0);
+
+        let req = Request::new(Body::empty());
+        assert!(filter.is_logged(&req, &ctx(200, "1000000000000000")));
+        assert!(!filter.is_logged(&req, &ctx(200, "9000000000000000")));
+        assert!(!filter.is_logged(&req, &ctx(404, "0000000000000000")));
+        assert!(filter.is_logged(&req, &ctx(500, "ffffffffffffffff")));
+
+        let mut var = LogFilter::default();
+        var.skip(Condition::Var(
+            Var::from_str("$`query_debug`").unwrap(),
+            ValuePattern::True,
+        ));
+        let req = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+        assert!(var.is_logged(&req("/?debug=0"), &ctx(200, "")));
+        assert!(!var.is_logged(&req("/?debug=1"), &ctx(200, "")));
+    }
+}
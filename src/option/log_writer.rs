@@ -0,0 +1,366 @@
+use crate::error_log::Level;
+use crate::log_error;
+#[cfg(unix)]
+use crate::option::Journald;
+use crate::option::{LogFile, Syslog, SyslogAddr};
+use lazy_static::lazy_static;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{self, AsyncWriteExt, Result, Stderr, Stdout};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::oneshot;
+use tokio::time::{self, Instant};
+
+lazy_static! {
+    // All running writers, flushed on shutdown
+    static ref WRITERS: Mutex<Vec<LogWriter>> = Mutex::new(vec![]);
+}
+
+// What to do when the queue is full
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Overflow {
+    // The response waits for space in the queue
+    #[default]
+    Block,
+    // Discard the line and count it
+    Drop,
+}
+
+// log {
+//     buffer 64K
+//     queue 8192
+//     flush 1s
+//     overflow drop
+// }
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogBuffer {
+    // Bytes collected before writing
+    pub size: usize,
+    // Lines waiting for the writer
+    pub queue: usize,
+    pub flush: Duration,
+    pub overflow: Overflow,
+}
+
+impl Default for LogBuffer {
+    fn default() -> Self {
+        Self {
+            size: 64 * 1024,
+            queue: 8192,
+            flush: Duration::from_secs(1),
+            overflow: Overflow::Block,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LogOutput {
+    File(LogFile),
+    Stdout(Stdout),
+    Stderr(Stderr),
+    Syslog(Syslog),
+    #[cfg(unix)]
+    Journald(Journald),
+}
+
+impl LogOutput {
+    pub fn stdout() -> Self {
+        LogOutput::Stdout(io::stdout())
+    }
+
+    pub fn stderr() -> Self {
+        LogOutput::Stderr(io::stderr())
+    }
+
+    fn name(&self) -> String {
+        match self {
+            LogOutput::File(file) => format!("'{}'", file.path().display()),
+            LogOutput::Stdout(_) => "stdout".to_string(),
+            LogOutput::Stderr(_) => "stderr".to_string(),
+            LogOutput::Syslog(syslog) => match syslog.addr() {
+                SyslogAddr::Unix(path) => format!("syslog unix:{}", path.display()),
+                SyslogAddr::Udp(addr) => format!("syslog udp://{}", addr),
+                SyslogAddr::Tcp(addr) => format!("syslog tcp://{}", addr),
+            },
+            #[cfg(unix)]
+            LogOutput::Journald(journald) => format!("journald {}", journald.path().display()),
+        }
+    }
+
+    // Whether the output keeps its own time and level
+    pub fn is_structured(&self) -> bool {
+        match self {
+            LogOutput::Syslog(_) => true,
+            #[cfg(unix)]
+            LogOutput::Journald(_) => true,
+            _ => false,
+        }
+    }
+
+    async fn write(&mut self, lines: &[(Level, String)]) -> Result<()> {
+        match self {
+            LogOutput::File(file) => file.write(concat(lines).as_bytes()).await,
+            LogOutput::Stdout(stdout) => {
+                stdout.write_all(concat(lines).as_bytes()).await?;
+                stdout.flush().await
+            }
+            LogOutput::Stderr(stderr) => {
+                stderr.write_all(concat(lines).as_bytes()).await?;
+                stderr.flush().await
+            }
+            // One message for each line, the rest are sent after a failure
+            LogOutput::Syslog(syslog) => {
+                let mut rst = Ok(());
+                for (level, line) in lines {
+                    rst = rst.and(syslog.send(*level, line.trim_end_matches('\n')).await);
+                }
+                rst
+            }
+            #[cfg(unix)]
+            LogOutput::Journald(journald) => {
+                let mut rst = Ok(());
+                for (level, line) in lines {
+                    rst = rst.and(journald.send(*level, line.trim_end_matches('\n')).await);
+                }
+                rst
+            }
+        }
+    }
+}
+
+fn concat(lines: &[(Level, String)]) -> String {
+    lines.iter().map(|(_, line)| line.as_str()).collect()
+}
+
+#[derive(Debug)]
+enum Message {
+    Line(Level, String),
+    Flush(oneshot::Sender<()>),
+}
+
+// Handle of a background task that writes log lines in batches
+#[derive(Debug, Clone)]
+pub struct LogWriter {
+    sender: mpsc::Sender<Message>,
+    overflow: Overflow,
+    dropped: Arc<AtomicU64>,
+}
+
+impl LogWriter {
+    pub fn spawn(output: LogOutput, buffer: &LogBuffer) -> Self {
+        Self::spawn_with(output, buffer, true)
+    }
+
+    // Failures of the error log cannot be written to itself
+    pub fn spawn_error_log(output: LogOutput, buffer: &LogBuffer) -> Self {
+        Self::spawn_with(output, buffer, false)
+    }
+
+    fn spawn_with(output: LogOutput, buffer: &LogBuffer, report: bool) -> Self {
+        let (sender, receiver) = mpsc::channel(buffer.queue.max(1));
+        let writer = Self {
+            sender,
+            overflow: buffer.overflow,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        tokio::spawn(run(
+            output,
+            receiver,
+            buffer.clone(),
+            writer.dropped.clone(),
+            report,
+        ));
+        WRITERS.lock().unwrap().push(writer.clone());
+
+        writer
+    }
+
+    // Whether lines are written with `write` and wait for the queue
+    pub fn is_blocking(&self) -> bool {
+        self.overflow == Overflow::Block
+    }
+
+    // Queue a line without waiting, it is dropped and counted if the queue is full
+    pub fn send(&self, level: Level, line: String) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(Message::Line(level, line)) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Queue a line and wait for space in the queue
+    pub async fn write(&self, level: Level, line: String) {
+        let _ = self.sender.send(Message::Line(level, line)).await;
+    }
+
+    // Write everything queued before this call
+    pub async fn flush(&self) {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.sender.send(Message::Flush(tx)).await;
+        let _ = rx.await;
+    }
+}
+
+// Flush all log writers, called before the process exits
+pub async fn flush_all() {
+    let writers = WRITERS.lock().unwrap().clone();
+    for writer in writers {
+        writer.flush().await;
+    }
+}
+
+async fn run(
+    mut output: LogOutput,
+    mut receiver: mpsc::Receiver<Message>,
+    buffer: LogBuffer,
+    dropped: Arc<AtomicU64>,
+    report: bool,
+) {
+    let mut buf = Batch::default();
+    let mut interval = time::interval_at(Instant::now() + buffer.flush, buffer.flush);
+    let mut reported = 0;
+
+    loop {
+        tokio::select! {
+            msg = receiver.recv() => match msg {
+                Some(Message::Line(level, line)) => {
+                    buf.size += line.len();
+                    buf.lines.push((level, line));
+                    if buf.size >= buffer.size {
+                        write(&mut output, &mut buf, report).await;
+                    }
+                }
+                Some(Message::Flush(done)) => {
+                    write(&mut output, &mut buf, report).await;
+                    let _ = done.send(());
+                }
+                None => {
+                    write(&mut output, &mut buf, report).await;
+                    break;
+                }
+            },
+            _ = interval.tick() => {
+                write(&mut output, &mut buf, report).await;
+
+                let count = dropped.load(Ordering::Relaxed);
+                if count > reported {
+                    crate::log_warn!(
+                        "Log queue of {} is full, dropped {} lines",
+                        output.name(),
+                        count - reported
+                    );
+                    reported = count;
+                }
+            }
+        }
+    }
+}
+
+// Lines collected before writing
+#[derive(Default)]
+struct Batch {
+    lines: Vec<(Level, String)>,
+    size: usize,
+}
+
+async fn write(output: &mut LogOutput, buf: &mut Batch, report: bool) {
+    if !buf.lines.is_empty() {
+        if let Err(err) = output.write(&buf.lines).await {
+            if report {
+                log_error!("Write log to {} failed: {}", output.name(), err);
+            }
+        }
+        buf.lines.clear();
+        buf.size = 0;
+    }
+}
+
+#[tokio::test]
+async fn test_log_writer() {
+    use tokio::fs;
+
+    let file = "./test-writer.log";
+    let buffer = LogBuffer {
+        size: 1024,
+        flush: Duration::from_secs(60),
+        ..Default::default()
+    };
+    let output = LogOutput::File(LogFile::open(file, None).await.unwrap());
+    let writer = LogWriter::spawn(output, &buffer);
+    writer.write(Level::Info, "a\n".to_string()).await;
+    writer.send(Level::Info, "b\n".to_string());
+
+    // Still in the buffer
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(fs::read_to_string(file).await.unwrap(), "");
+
+    writer.flush().await;
+    let content = fs::read_to_string(file).await.unwrap();
+    fs::remove_file(file).await.unwrap();
+    assert_eq!(content, "a\nb\n");
+}
+
+#[tokio::test]
+async fn test_log_writer_drop() {
+    let file = "./test-writer-drop.log";
+    let buffer = LogBuffer {
+        queue: 1,
+        overflow: Overflow::Drop,
+        ..Default::default()
+    };
+    let output = LogOutput::File(LogFile::open(file, None).await.unwrap());
+    let writer = LogWriter::spawn(output, &buffer);
+
+    // The writer task has not run yet on this thread
+    for _ in 0..3 {
+        writer.send(Level::Info, "a\n".to_string());
+    }
+    writer.flush().await;
+    tokio::fs::remove_file(file).await.unwrap();
+    assert_eq!(writer.dropped.load(Ordering::Relaxed), 2);
+}
+
+#[tokio::test]
+async fn test_log_writer_block() {
+    use tokio::fs;
+
+    let file = "./test-writer-block.log";
+    let buffer = LogBuffer {
+        queue: 1,
+        ..Default::default()
+    };
+    let output = LogOutput::File(LogFile::open(file, None).await.unwrap());
+    let writer = LogWriter::spawn(output, &buffer);
+
+    // Each line waits for the writer instead of being dropped
+    for i in 0..4 {
+        writer.write(Level::Info, format!("{}\n", i)).await;
+    }
+    writer.flush().await;
+    let content = fs::read_to_string(file).await.unwrap();
+    fs::remove_file(file).await.unwrap();
+    assert_eq!(content, "0\n1\n2\n3\n");
+    assert_eq!(writer.dropped.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn test_log_output_syslog() {
+    use tokio::net::UdpSocket;
+
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = SyslogAddr::Udp(server.local_addr().unwrap());
+    let mut output = LogOutput::Syslog(Syslog::new(addr, 1, "see".to_string()));
+
+    // Too long for a datagram
+    let lines = [
+        (Level::Info, "a\n".to_string()),
+        (Level::Info, "x".repeat(70000)),
+        (Level::Info, "b\n".to_string()),
+    ];
+    assert!(output.write(&lines).await.is_err());
+    let mut buf = [0; 1024];
+    for expected in ["a", "b"] {
+        let n = server.recv(&mut buf).await.unwrap();
+        assert!(String::from_utf8_lossy(&buf[..n]).ends_with(&format!(" - - {}", expected)));
+    }
+}
@@ -0,0 +1,147 @@
+use crate::config::{Context, Var};
+use crate::util;
+use globset::GlobMatcher;
+use hyper::{Body, Request};
+use regex::{Regex, RegexBuilder};
+use std::collections::HashMap;
+
+pub type Maps = HashMap<String, Map>;
+
+// Derive a variable from the value of another variable
+// map $`header_user-agent` $`is_mobile` {
+//     ~iPhone 1
+//     default 0
+// }
+#[derive(Debug, Clone)]
+pub struct Map {
+    source: Var<String>,
+    exact: HashMap<String, Var<String>>,
+    wildcard: Vec<(GlobMatcher, Var<String>)>,
+    regex: Vec<(Regex, Var<String>)>,
+    default: Option<Var<String>>,
+}
+
+impl Map {
+    pub fn new(source: Var<String>) -> Self {
+        Self {
+            source,
+            exact: HashMap::new(),
+            wildcard: vec![],
+            regex: vec![],
+            default: None,
+        }
+    }
+
+    // Keys are matched in order: exact, wildcard, regex
+    // `*` matches any characters, `~` is a regular expression and `~*` ignores case
+    pub fn insert(&mut self, key: &str, value: Var<String>) -> Result<(), String> {
+        if key == "default" {
+            self.default = Some(value);
+        } else if let Some(reg) = key.strip_prefix("~*") {
+            let reg = RegexBuilder::new(reg)
+                .case_insensitive(true)
+                .build()
+                .map_err(|err| format!("Cannot parse `{}` to regular expression\n{}", reg, err))?;
+            self.regex.push((reg, value));
+        } else if let Some(reg) = key.strip_prefix('~') {
+            self.regex.push((util::to_regex(reg)?, value));
+        } else if key.contains('*') {
+            self.wildcard.push((util::to_glob(key)?, value));
+        } else {
+            self.exact.insert(key.to_string(), value);
+        }
+        Ok(())
+    }
+
+    // All variables used by this map
+    pub fn names(&self) -> Vec<&str> {
+        let mut names = self.source.names();
+        let values = self
+            .exact
+            .values()
+            .chain(self.wildcard.iter().map(|(_, v)| v))
+            .chain(self.regex.iter().map(|(_, v)| v))
+            .chain(self.default.iter());
+        for value in values {
+            names.extend(value.names());
+        }
+        names
+    }
+
+    pub fn value(&self, req: &Request<Body>, ctx: &Context) -> String {
+        let source = self.source.render(req, ctx);
+
+        let value = self
+            .exact
+            .get(&source)
+            .or_else(|| {
+                self.wildcard
+                    .iter()
+                    .find(|(m, _)| m.is_match(&source))
+                    .map(|(_, v)| v)
+            })
+            .or_else(|| {
+                self.regex
+                    .iter()
+                    .find(|(reg, _)| reg.is_match(&source))
+                    .map(|(_, v)| v)
+            })
+            .or(self.default.as_ref());
+
+        match value {
+            Some(value) => value.render(req, ctx),
+            None => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    fn var(s: &str) -> Var<String> {
+        Var::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn map() {
+        let mut map = Map::new(var("$`header_host`"));
+        map.insert("example.com", var("exact")).unwrap();
+        map.insert("*.example.com", var("wildcard")).unwrap();
+        map.insert("~^api\\.", var("regex")).unwrap();
+        map.insert("~*^ADMIN\\.", var("$`path`")).unwrap();
+        map.insert("default", var("default")).unwrap();
+        assert!(map.insert("~(", var("")).is_err());
+
+        let ctx = Context::default();
+        let value = |host: &str| {
+            let req = Request::builder()
+                .uri("/a")
+                .header("host", host)
+                .body(Body::empty())
+                .unwrap();
+            map.value(&req, &ctx)
+        };
+
+        assert_eq!(value("example.com"), "exact");
+        assert_eq!(value("www.example.com"), "wildcard");
+        assert_eq!(value("a.b.example.com"), "wildcard");
+        assert_eq!(value("api.test.com"), "regex");
+        assert_eq!(value("admin.test.com"), "/a");
+        assert_eq!(value("test.com"), "default");
+    }
+
+    #[test]
+    fn no_default() {
+        let mut map = Map::new(var("$`method`"));
+        map.insert("GET", var("1")).unwrap();
+
+        let ctx = Context::default();
+        let req = Request::builder()
+            .method("POST")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(map.value(&req, &ctx), "");
+    }
+}
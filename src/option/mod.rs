@@ -1,17 +1,49 @@
 mod auth;
+mod auth_request;
 mod compress;
 mod directory;
+mod geoip;
+mod htpasswd;
 mod index;
+#[cfg(unix)]
+mod journald;
+mod jwt;
+mod lockout;
 mod log;
+mod log_file;
+mod log_filter;
+mod log_writer;
+mod map;
 mod method;
+mod oidc;
 mod proxy;
+mod proxy_protocol;
+mod real_ip;
 mod rewrite;
+mod session;
+mod syslog;
 
 pub use auth::*;
+pub use auth_request::*;
 pub use compress::*;
 pub use directory::*;
+pub use geoip::*;
+pub use htpasswd::*;
 pub use index::*;
+#[cfg(unix)]
+pub use journald::*;
+pub use jwt::*;
+pub use lockout::*;
 pub use log::*;
+pub use log_file::*;
+pub use log_filter::*;
+pub use log_writer::*;
+pub use map::*;
 pub use method::*;
+pub use oidc::*;
 pub use proxy::*;
+pub use proxy_protocol::*;
+pub use real_ip::*;
 pub use rewrite::*;
+pub use session::*;
+pub use syslog::*;
@@ -0,0 +1,597 @@
+use crate::client;
+use crate::config::{default, Context};
+use crate::option::{local_uri, Jwks, Jwt};
+use crate::util::{ct_eq, parse_form, urlencode};
+use crate::{log_error, log_info, ResponseExt};
+use hyper::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+const DISCOVERY: &str = "/.well-known/openid-configuration";
+
+// Endpoints and keys of the identity provider
+#[derive(Debug)]
+struct Provider {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks: Jwks,
+    time: Instant,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum OidcError {
+    // Wrong state, code or ID token
+    Invalid(String),
+    // Not in the allow-lists
+    Denied(String),
+    // The identity provider cannot be used
+    Provider(String),
+}
+
+impl OidcError {
+    pub fn response(&self, ctx: &Context) -> Response<Body> {
+        match self {
+            OidcError::Invalid(err) => {
+                log_info!("OIDC login failed: {}, request_id: {}", err, ctx.request_id);
+                Response::error(StatusCode::BAD_REQUEST)
+            }
+            OidcError::Denied(err) => {
+                log_info!("OIDC login denied: {}, request_id: {}", err, ctx.request_id);
+                Response::error(StatusCode::FORBIDDEN)
+            }
+            OidcError::Provider(err) => {
+                log_error!(
+                    "OIDC provider error: {}, request_id: {}",
+                    err,
+                    ctx.request_id
+                );
+                Response::error(StatusCode::BAD_GATEWAY)
+            }
+        }
+    }
+}
+
+// Authorization code flow with PKCE
+#[derive(Debug, Clone)]
+pub struct Oidc {
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_uri: Uri,
+    scope: String,
+    user_claim: String,
+    groups_claim: String,
+    // Every claim must have one of the values
+    allow_claims: Vec<(String, Vec<String>)>,
+    allow_groups: Vec<String>,
+    // Checks `iss`, `aud`, `exp` and `iat`, HS256 tokens are signed with the client secret
+    jwt: Jwt,
+    // Discovered on the first login
+    provider: Arc<RwLock<Option<Arc<Provider>>>>,
+}
+
+impl Oidc {
+    pub fn new(issuer: &str, client_id: &str, redirect_uri: Uri) -> Result<Self, String> {
+        if redirect_uri.scheme().is_none() || redirect_uri.authority().is_none() {
+            return Err(format!(
+                "Expected an absolute url, e.g. `https://{}`",
+                redirect_uri
+            ));
+        }
+        Ok(Self {
+            issuer: issuer.to_string(),
+            client_id: client_id.to_string(),
+            client_secret: None,
+            redirect_uri,
+            scope: default::OIDC_SCOPE.to_string(),
+            user_claim: default::OIDC_USER_CLAIM.to_string(),
+            groups_claim: default::OIDC_GROUPS_CLAIM.to_string(),
+            allow_claims: vec![],
+            allow_groups: vec![],
+            jwt: Jwt::default()
+                .issuer(issuer)
+                .audience(client_id)
+                .require("exp")
+                .require("iat"),
+            provider: Arc::new(RwLock::new(None)),
+        })
+    }
+
+    pub fn client_secret(mut self, secret: &str) -> Self {
+        self.client_secret = Some(secret.to_string());
+        self.jwt = self.jwt.secret(secret);
+        self
+    }
+
+    pub fn scope(mut self, scope: &[&str]) -> Result<Self, String> {
+        if !scope.contains(&"openid") {
+            return Err("`scope` must contain `openid`".to_string());
+        }
+        self.scope = scope.join(" ");
+        Ok(self)
+    }
+
+    pub fn user_claim(mut self, claim: &str) -> Self {
+        self.user_claim = claim.to_string();
+        self
+    }
+
+    pub fn groups_claim(mut self, claim: &str) -> Self {
+        self.groups_claim = claim.to_string();
+        self
+    }
+
+    pub fn allow_claim(mut self, claim: &str, values: Vec<String>) -> Self {
+        self.allow_claims.push((claim.to_string(), values));
+        self
+    }
+
+    pub fn allow_groups(mut self, groups: Vec<String>) -> Self {
+        self.allow_groups.extend(groups);
+        self
+    }
+
+    pub fn is_callback(&self, req: &Request<Body>) -> bool {
+        req.uri().path() == self.redirect_uri.path()
+    }
+
+    // Location of the provider login, and the state to keep until the callback
+    pub async fn authorize(&self, return_uri: &str) -> Result<(String, String), OidcError> {
+        let provider = self.provider(false).await?;
+        let (state, nonce, verifier) = (random_token()?, random_token()?, random_token()?);
+        let challenge =
+            base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD);
+
+        let endpoint = &provider.authorization_endpoint;
+        let location = format!(
+            "{}{}response_type=code&client_id={}&redirect_uri={}&scope={}&state={}&nonce={}&code_challenge={}&code_challenge_method=S256",
+            endpoint,
+            if endpoint.contains('?') { '&' } else { '?' },
+            urlencode(&self.client_id),
+            urlencode(&self.redirect_uri.to_string()),
+            urlencode(&self.scope),
+            state,
+            nonce,
+            challenge
+        );
+        Ok((
+            location,
+            format!("{} {} {} {}", state, nonce, verifier, local_uri(return_uri)),
+        ))
+    }
+
+    // Get the user name and the page before the login
+    pub async fn callback(
+        &self,
+        req: &Request<Body>,
+        saved: &str,
+        now: u64,
+    ) -> Result<(String, String), OidcError> {
+        let invalid = |s: &str| OidcError::Invalid(s.to_string());
+        let saved = saved.splitn(4, ' ').collect::<Vec<&str>>();
+        let (state, nonce, verifier, return_uri) = match saved[..] {
+            [state, nonce, verifier, return_uri] => (state, nonce, verifier, return_uri),
+            _ => return Err(invalid("Wrong state cookie")),
+        };
+
+        let query = parse_form(req.uri().query().unwrap_or_default());
+        if let Some(error) = query.get("error") {
+            return Err(OidcError::Denied(format!("Provider returned `{}`", error)));
+        }
+        match query.get("state") {
+            Some(s) if ct_eq(s.as_bytes(), state.as_bytes()) => {}
+            _ => return Err(invalid("Wrong state")),
+        }
+        let code = query.get("code").ok_or_else(|| invalid("Missing code"))?;
+
+        let token = self.exchange(code, verifier).await?;
+        let claims = self.verify(&token, now).await?;
+        if claims.get("nonce").and_then(|v| v.as_str()) != Some(nonce) {
+            return Err(invalid("Wrong nonce"));
+        }
+        self.check_allowed(&claims)?;
+
+        let user = claim_values(claims.get(&self.user_claim))
+            .into_iter()
+            .next()
+            .ok_or_else(|| invalid(&format!("Missing claim `{}`", self.user_claim)))?;
+        Ok((user, local_uri(return_uri).to_string()))
+    }
+
+    // Trade the code for an ID token
+    async fn exchange(&self, code: &str, verifier: &str) -> Result<String, OidcError> {
+        let provider = self.provider(false).await?;
+        let body = format!(
+            "grant_type=authorization_code&code={}&redirect_uri={}&client_id={}&code_verifier={}",
+            urlencode(code),
+            urlencode(&self.redirect_uri.to_string()),
+            urlencode(&self.client_id),
+            verifier
+        );
+        let mut req = Request::new(Body::from(body));
+        *req.method_mut() = Method::POST;
+        *req.uri_mut() = to_uri(&provider.token_endpoint)?;
+        let headers = req.headers_mut();
+        headers.insert(
+            CONTENT_TYPE,
+            "application/x-www-form-urlencoded".parse().unwrap(),
+        );
+        // client_secret_basic
+        if let Some(secret) = &self.client_secret {
+            let credential = format!("{}:{}", urlencode(&self.client_id), urlencode(secret));
+            let value = format!("Basic {}", base64::encode(credential));
+            let value = value
+                .parse()
+                .map_err(|_| OidcError::Provider("Wrong client secret".to_string()))?;
+            headers.insert(AUTHORIZATION, value);
+        }
+
+        let (status, value) = fetch_json(req).await?;
+        if status.is_client_error() {
+            let error = value["error"].as_str().unwrap_or_default();
+            return Err(OidcError::Invalid(format!(
+                "Token request returned {} `{}`",
+                status, error
+            )));
+        }
+        if !status.is_success() {
+            return Err(OidcError::Provider(format!(
+                "Token request returned {}",
+                status
+            )));
+        }
+        value["id_token"]
+            .as_str()
+            .map(|s| s.to_string())
+            .ok_or_else(|| OidcError::Provider("Missing `id_token`".to_string()))
+    }
+
+    // Fetch the keys again when they may have been rotated
+    async fn verify(&self, token: &str, now: u64) -> Result<Map<String, Value>, OidcError> {
+        let provider = self.provider(false).await?;
+        match self.jwt.verify_with(token, now, Some(&provider.jwks)) {
+            Err(_) if provider.time.elapsed() >= default::OIDC_REFRESH => {
+                let provider = self.provider(true).await?;
+                self.jwt.verify_with(token, now, Some(&provider.jwks))
+            }
+            rst => rst,
+        }
+        .map_err(OidcError::Invalid)
+    }
+
+    fn check_allowed(&self, claims: &Map<String, Value>) -> Result<(), OidcError> {
+        for (name, allowed) in &self.allow_claims {
+            let values = claim_values(claims.get(name));
+            if !values.iter().any(|value| allowed.contains(value)) {
+                return Err(OidcError::Denied(format!(
+                    "Claim `{}` is not allowed",
+                    name
+                )));
+            }
+        }
+        if !self.allow_groups.is_empty() {
+            let groups = claim_values(claims.get(&self.groups_claim));
+            if !groups.iter().any(|group| self.allow_groups.contains(group)) {
+                return Err(OidcError::Denied("No allowed group".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    async fn provider(&self, refresh: bool) -> Result<Arc<Provider>, OidcError> {
+        if !refresh {
+            if let Some(provider) = self.provider.read().unwrap().clone() {
+                return Ok(provider);
+            }
+        }
+
+        let url = format!("{}{}", self.issuer.trim_end_matches('/'), DISCOVERY);
+        let value = get_json(&url).await?;
+        if value["issuer"].as_str() != Some(&self.issuer) {
+            return Err(OidcError::Provider(format!("Wrong issuer in '{}'", url)));
+        }
+        let endpoint = |name: &str| {
+            value[name]
+                .as_str()
+                .map(|s| s.to_string())
+                .ok_or_else(|| OidcError::Provider(format!("Missing `{}` in '{}'", name, url)))
+        };
+        let jwks_uri = endpoint("jwks_uri")?;
+        // HS256 only needs the client secret
+        let jwks = match Jwks::new(&get_json(&jwks_uri).await?.to_string()) {
+            Ok(jwks) => jwks,
+            Err(_) if self.client_secret.is_some() => Jwks::default(),
+            Err(err) => {
+                return Err(OidcError::Provider(format!("{} in '{}'", err, jwks_uri)));
+            }
+        };
+
+        let provider = Arc::new(Provider {
+            authorization_endpoint: endpoint("authorization_endpoint")?,
+            token_endpoint: endpoint("token_endpoint")?,
+            jwks,
+            time: Instant::now(),
+        });
+        *self.provider.write().unwrap() = Some(provider.clone());
+        Ok(provider)
+    }
+}
+
+fn to_uri(url: &str) -> Result<Uri, OidcError> {
+    url.parse()
+        .map_err(|_| OidcError::Provider(format!("Wrong url '{}'", url)))
+}
+
+async fn get_json(url: &str) -> Result<Value, OidcError> {
+    let mut req = Request::new(Body::empty());
+    *req.uri_mut() = to_uri(url)?;
+    match fetch_json(req).await? {
+        (status, value) if status.is_success() => Ok(value),
+        (status, _) => Err(OidcError::Provider(format!(
+            "'{}' returned {}",
+            url, status
+        ))),
+    }
+}
+
+async fn fetch_json(mut req: Request<Body>) -> Result<(StatusCode, Value), OidcError> {
+    let url = req.uri().to_string();
+    let error = |err: String| OidcError::Provider(format!("Request to '{}' failed: {}", url, err));
+    req.headers_mut()
+        .insert(ACCEPT, "application/json".parse().unwrap());
+    let res = client::request(req)
+        .await
+        .map_err(|err| error(err.to_string()))?;
+    let status = Response::status(&res);
+    let body = hyper::body::to_bytes(res.into_body())
+        .await
+        .map_err(|err| error(err.to_string()))?;
+    let value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    Ok((status, value))
+}
+
+// 256 random bits
+fn random_token() -> Result<String, OidcError> {
+    let mut bytes = [0; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| OidcError::Provider("Cannot generate random bytes".to_string()))?;
+    Ok(base64::encode_config(bytes, base64::URL_SAFE_NO_PAD))
+}
+
+// A claim can be a string, a list or another JSON value
+fn claim_values(value: Option<&Value>) -> Vec<String> {
+    let to_string = |value: &Value| match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    };
+    match value {
+        None | Some(Value::Null) => vec![],
+        Some(Value::Array(values)) => values.iter().map(to_string).collect(),
+        Some(value) => vec![to_string(value)],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::Server;
+    use ring::signature::{self, EcdsaKeyPair, KeyPair};
+    use std::convert::Infallible;
+    use std::sync::Mutex;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn encode(bytes: &[u8]) -> String {
+        base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+    }
+
+    // Identity provider that signs ES256 ID tokens
+    struct Mock {
+        issuer: String,
+        key: EcdsaKeyPair,
+        // Challenge and nonce of the last authorization
+        login: Mutex<(String, String)>,
+        groups: Mutex<Vec<&'static str>>,
+    }
+
+    impl Mock {
+        fn id_token(&self, nonce: &str) -> String {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            let claims = serde_json::json!({
+                "iss": self.issuer,
+                "aud": "see",
+                "sub": "u1",
+                "email": "tom@example.com",
+                "email_verified": true,
+                "groups": *self.groups.lock().unwrap(),
+                "nonce": nonce,
+                "exp": now.as_secs() + 300,
+                "iat": now.as_secs(),
+            });
+            let message = format!(
+                "{}.{}",
+                encode(br#"{"alg":"ES256","kid":"k1"}"#),
+                encode(claims.to_string().as_bytes())
+            );
+            let sig = self
+                .key
+                .sign(&SystemRandom::new(), message.as_bytes())
+                .unwrap();
+            format!("{}.{}", message, encode(sig.as_ref()))
+        }
+
+        async fn handle(&self, req: Request<Body>) -> Response<Body> {
+            let json = |value: Value| Response::new(Body::from(value.to_string()));
+            match req.uri().path() {
+                DISCOVERY => json(serde_json::json!({
+                    "issuer": self.issuer,
+                    "authorization_endpoint": format!("{}/auth", self.issuer),
+                    "token_endpoint": format!("{}/token", self.issuer),
+                    "jwks_uri": format!("{}/jwks", self.issuer),
+                })),
+                "/jwks" => {
+                    let point = self.key.public_key().as_ref();
+                    json(serde_json::json!({"keys": [{
+                        "kty": "EC", "crv": "P-256", "kid": "k1",
+                        "x": encode(&point[1..33]), "y": encode(&point[33..]),
+                    }]}))
+                }
+                "/token" => {
+                    let basic = format!("Basic {}", base64::encode("see:secret"));
+                    let authorized = req.headers()[AUTHORIZATION] == basic.as_str();
+                    let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                    let form = parse_form(&String::from_utf8_lossy(&body));
+                    let (challenge, nonce) = self.login.lock().unwrap().clone();
+                    let verifier = Sha256::digest(form["code_verifier"].as_bytes());
+                    if authorized
+                        && form["code"] == "good"
+                        && form["redirect_uri"] == "http://docs.example.com/callback"
+                        && encode(&verifier) == challenge
+                    {
+                        json(serde_json::json!({ "id_token": self.id_token(&nonce) }))
+                    } else {
+                        json(serde_json::json!({ "error": "invalid_grant" }))
+                            .status(StatusCode::BAD_REQUEST)
+                    }
+                }
+                _ => Response::error(StatusCode::NOT_FOUND),
+            }
+        }
+    }
+
+    fn mock() -> Arc<Mock> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+            .unwrap();
+        let mock = Arc::new(Mock {
+            issuer: format!("http://{}", listener.local_addr().unwrap()),
+            key: EcdsaKeyPair::from_pkcs8(
+                &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+                pkcs8.as_ref(),
+            )
+            .unwrap(),
+            login: Mutex::new(Default::default()),
+            groups: Mutex::new(vec!["docs", "dev"]),
+        });
+
+        let m = mock.clone();
+        let service = make_service_fn(move |_| {
+            let m = m.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let m = m.clone();
+                    async move { Ok::<_, Infallible>(m.handle(req).await) }
+                }))
+            }
+        });
+        tokio::spawn(Server::from_tcp(listener).unwrap().serve(service));
+        mock
+    }
+
+    fn callback(query: &str) -> Request<Body> {
+        Request::builder()
+            .uri(format!("/callback?{}", query))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    // Log in and return the callback result
+    async fn login(oidc: &Oidc, mock: &Mock, code: &str) -> Result<(String, String), OidcError> {
+        let (location, saved) = oidc.authorize("/docs?a=1").await.unwrap();
+        assert!(location.starts_with(&format!("{}/auth?response_type=code&client_id=see&redirect_uri=http%3A%2F%2Fdocs.example.com%2Fcallback&scope=openid%20email%20groups&", mock.issuer)));
+        let query = parse_form(location.split_once('?').unwrap().1);
+        assert_eq!(query["code_challenge_method"], "S256");
+        *mock.login.lock().unwrap() = (query["code_challenge"].clone(), query["nonce"].clone());
+
+        let req = callback(&format!("code={}&state={}", code, query["state"]));
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        oidc.callback(&req, &saved, now).await
+    }
+
+    #[tokio::test]
+    async fn flow() {
+        let mock = mock();
+        let oidc = Oidc::new(
+            &mock.issuer,
+            "see",
+            "http://docs.example.com/callback".parse().unwrap(),
+        )
+        .unwrap()
+        .client_secret("secret")
+        .scope(&["openid", "email", "groups"])
+        .unwrap()
+        .user_claim("email")
+        .allow_claim("email_verified", vec!["true".to_string()])
+        .allow_groups(vec!["docs".to_string()]);
+        assert!(oidc.is_callback(&callback("")));
+        assert!(oidc.clone().scope(&["email"]).is_err());
+
+        let (user, location) = login(&oidc, &mock, "good").await.unwrap();
+        assert_eq!(user, "tom@example.com");
+        assert_eq!(location, "/docs?a=1");
+
+        // Wrong code, wrong state and a provider error
+        assert!(matches!(
+            login(&oidc, &mock, "bad").await,
+            Err(OidcError::Invalid(_))
+        ));
+        // Never back to another site
+        let (_, saved) = oidc.authorize("//evil.example/x").await.unwrap();
+        assert!(saved.ends_with(" /"));
+        let (_, saved) = oidc.authorize("/").await.unwrap();
+        let req = callback("code=good&state=other");
+        assert_eq!(
+            oidc.callback(&req, &saved, 0).await.unwrap_err(),
+            OidcError::Invalid("Wrong state".to_string())
+        );
+        let req = callback("error=access_denied");
+        assert!(matches!(
+            oidc.callback(&req, &saved, 0).await,
+            Err(OidcError::Denied(_))
+        ));
+
+        // Not in the allowed groups
+        *mock.groups.lock().unwrap() = vec!["dev"];
+        assert_eq!(
+            login(&oidc, &mock, "good").await.unwrap_err(),
+            OidcError::Denied("No allowed group".to_string())
+        );
+
+        // ID tokens must have `exp` and `iat`
+        let hs256 = |claims: Value| {
+            let message = format!(
+                "{}.{}",
+                encode(br#"{"alg":"HS256"}"#),
+                encode(claims.to_string().as_bytes())
+            );
+            let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, b"secret");
+            let sig = ring::hmac::sign(&key, message.as_bytes());
+            format!("{}.{}", message, encode(sig.as_ref()))
+        };
+        let claims = serde_json::json!({"iss": mock.issuer, "aud": "see", "exp": 200, "iat": 100});
+        assert!(oidc.verify(&hs256(claims.clone()), 150).await.is_ok());
+        for name in ["exp", "iat"] {
+            let mut claims = claims.clone();
+            claims.as_object_mut().unwrap().remove(name);
+            assert_eq!(
+                oidc.verify(&hs256(claims), 150).await.unwrap_err(),
+                OidcError::Invalid(format!("Missing `{}`", name))
+            );
+        }
+
+        let other = Oidc::new("http://127.0.0.1:1", "see", "http://a/cb".parse().unwrap()).unwrap();
+        assert!(matches!(
+            other.authorize("/").await,
+            Err(OidcError::Provider(_))
+        ));
+    }
+}
@@ -1,7 +1,12 @@
-use crate::config::{Headers, Setting, SiteConfig, Var};
-use crate::{client, headers_merge, response_error_page};
+use crate::config::{Context, Headers, Setting, SiteConfig, Var};
+use crate::{client, headers_merge, log_error, response_error_page};
 use hyper::header::ACCEPT_ENCODING;
 use hyper::{header::HOST, Body, Method, Request, Response, StatusCode, Uri};
+use std::time::{Duration, Instant};
+
+// Time spent waiting for the upstream response header
+#[derive(Debug, Clone, Copy)]
+pub struct UpstreamTime(pub Duration);
 
 #[derive(Debug, Clone)]
 pub struct Proxy {
@@ -11,14 +16,38 @@ pub struct Proxy {
 }
 
 impl Proxy {
-    pub async fn request(self, mut req: Request<Body>, config: &SiteConfig) -> Response<Body> {
+    pub async fn request(
+        self,
+        mut req: Request<Body>,
+        config: &SiteConfig,
+        ctx: &Context,
+    ) -> Response<Body> {
         let encoding = req.headers().get(ACCEPT_ENCODING).cloned();
 
-        let url = self.url.map(|s, r| {
-            let result = r.replace(s, &req);
-            result.parse::<Uri>().unwrap()
-        });
-        *req.uri_mut() = url;
+        let url = match self.url {
+            Var::None(url) => url,
+            Var::Some(t) => {
+                let result = t.render(&req, ctx);
+                match result.parse::<Uri>() {
+                    Ok(url) => url,
+                    Err(err) => {
+                        log_error!(
+                            "Invalid proxy url '{}': {}, request_id: {}",
+                            result,
+                            err,
+                            ctx.request_id
+                        );
+                        return response_error_page(
+                            encoding.as_ref(),
+                            config,
+                            StatusCode::BAD_GATEWAY,
+                        )
+                        .await;
+                    }
+                }
+            }
+        };
+        *req.uri_mut() = url.clone();
 
         if let Some(method) = self.method {
             *req.method_mut() = method;
@@ -30,13 +59,21 @@ impl Proxy {
         // todo
         if let Setting::Value(headers) = self.headers {
             let mut h = req.headers().clone();
-            headers_merge(&mut h, headers, &req);
+            headers_merge(&mut h, headers, &req, ctx);
             *req.headers_mut() = h;
         }
 
-        match client::request(req).await {
+        let start = Instant::now();
+        let mut res = match client::request(req).await {
             Ok(res) => res,
             Err(err) => {
+                log_error!(
+                    "Proxy request to '{}' failed: {}, client: {}, request_id: {}",
+                    url,
+                    err,
+                    ctx.conn.remote,
+                    ctx.request_id
+                );
                 let status = if err.is_timeout() {
                     StatusCode::GATEWAY_TIMEOUT
                 } else {
@@ -44,6 +81,8 @@ impl Proxy {
                 };
                 response_error_page(encoding.as_ref(), config, status).await
             }
-        }
+        };
+        res.extensions_mut().insert(UpstreamTime(start.elapsed()));
+        res
     }
 }
@@ -0,0 +1,249 @@
+use crate::config::default;
+use crate::matcher::Cidr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// The longest v1 header, including CRLF
+const V1_MAX: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+// PROXY protocol header sent by a load balancer before TLS or HTTP
+#[derive(Debug, Clone)]
+pub struct ProxyProtocol {
+    trusted: Vec<Cidr>,
+    timeout: Duration,
+}
+
+impl ProxyProtocol {
+    pub fn new(trusted: Vec<Cidr>) -> Self {
+        Self {
+            trusted,
+            timeout: default::PROXY_PROTOCOL_TIMEOUT,
+        }
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // Sites on the same listener share it
+    pub fn merge(&mut self, other: &Self) {
+        self.trusted.extend(other.trusted.iter().copied());
+        self.timeout = self.timeout.max(other.timeout);
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    // Source and destination addresses, `None` for health checks of the proxy itself
+    pub async fn read<R: AsyncRead + Unpin>(
+        &self,
+        stream: &mut R,
+    ) -> Result<Option<(SocketAddr, SocketAddr)>, String> {
+        match timeout(self.timeout, read_header(stream)).await {
+            Ok(rst) => rst,
+            Err(_) => Err("Timed out".to_string()),
+        }
+    }
+}
+
+async fn read_header<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> Result<Option<(SocketAddr, SocketAddr)>, String> {
+    let mut buf = vec![0; V1_PREFIX.len()];
+    stream
+        .read_exact(&mut buf)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    if buf == V1_PREFIX {
+        // Byte by byte, nothing after the header may be consumed
+        while !buf.ends_with(b"\r\n") {
+            if buf.len() == V1_MAX {
+                return Err("Header too long".to_string());
+            }
+            let byte = stream.read_u8().await.map_err(|err| err.to_string())?;
+            buf.push(byte);
+        }
+        return parse_v1(&buf[..buf.len() - 2]);
+    }
+
+    if buf != V2_SIGNATURE[..buf.len()] {
+        return Err("Missing header".to_string());
+    }
+    buf.resize(16, 0);
+    stream
+        .read_exact(&mut buf[V1_PREFIX.len()..])
+        .await
+        .map_err(|err| err.to_string())?;
+    if buf[..12] != *V2_SIGNATURE {
+        return Err("Missing header".to_string());
+    }
+    let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    let mut data = vec![0; len];
+    stream
+        .read_exact(&mut data)
+        .await
+        .map_err(|err| err.to_string())?;
+    parse_v2(buf[12], buf[13], &data)
+}
+
+// `PROXY TCP4 192.0.2.1 192.0.2.2 56324 443`
+fn parse_v1(line: &[u8]) -> Result<Option<(SocketAddr, SocketAddr)>, String> {
+    let line = std::str::from_utf8(line).map_err(|_| "Invalid v1 header".to_string())?;
+    let parts = line.split(' ').collect::<Vec<&str>>();
+    match parts.get(1).copied() {
+        Some("UNKNOWN") => return Ok(None),
+        Some("TCP4") | Some("TCP6") if parts.len() == 6 => {}
+        _ => return Err(format!("Invalid v1 header '{}'", line)),
+    }
+    let addr = |ip: &str, port: &str| -> Result<SocketAddr, String> {
+        let ip = ip
+            .parse::<IpAddr>()
+            .map_err(|_| format!("Invalid v1 address '{}'", ip))?;
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| format!("Invalid v1 port '{}'", port))?;
+        if ip.is_ipv4() != (parts[1] == "TCP4") {
+            return Err(format!("Invalid v1 address '{}'", ip));
+        }
+        Ok(SocketAddr::new(ip, port))
+    };
+    Ok(Some((addr(parts[2], parts[4])?, addr(parts[3], parts[5])?)))
+}
+
+fn parse_v2(
+    version: u8,
+    family: u8,
+    data: &[u8],
+) -> Result<Option<(SocketAddr, SocketAddr)>, String> {
+    if version >> 4 != 2 {
+        return Err(format!("Unsupported version {}", version >> 4));
+    }
+    match version & 0x0f {
+        // LOCAL
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        cmd => return Err(format!("Unsupported command {}", cmd)),
+    }
+    let port = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+    match family >> 4 {
+        // AF_INET
+        1 if data.len() >= 12 => {
+            let src = Ipv4Addr::new(data[0], data[1], data[2], data[3]);
+            let dst = Ipv4Addr::new(data[4], data[5], data[6], data[7]);
+            Ok(Some((
+                SocketAddr::new(src.into(), port(8)),
+                SocketAddr::new(dst.into(), port(10)),
+            )))
+        }
+        // AF_INET6
+        2 if data.len() >= 36 => {
+            let mut src = [0; 16];
+            let mut dst = [0; 16];
+            src.copy_from_slice(&data[..16]);
+            dst.copy_from_slice(&data[16..32]);
+            Ok(Some((
+                SocketAddr::new(Ipv6Addr::from(src).into(), port(32)),
+                SocketAddr::new(Ipv6Addr::from(dst).into(), port(34)),
+            )))
+        }
+        1 | 2 => Err("Invalid v2 address length".to_string()),
+        // AF_UNSPEC or AF_UNIX, nothing to use
+        _ => Ok(None),
+    }
+}
+
+#[tokio::test]
+async fn test_proxy_protocol() {
+    let proxy = ProxyProtocol::new(vec!["10.0.0.0/8".parse().unwrap()]);
+    assert!(proxy.is_trusted("10.1.1.1".parse().unwrap()));
+    assert!(!proxy.is_trusted("11.1.1.1".parse().unwrap()));
+
+    let read = |data: Vec<u8>| {
+        let proxy = proxy.clone();
+        async move {
+            let mut stream = &data[..];
+            let addrs = proxy.read(&mut stream).await;
+            addrs.map(|addrs| {
+                let rest = String::from_utf8_lossy(stream).to_string();
+                (
+                    addrs.map(|(src, dst)| (src.to_string(), dst.to_string())),
+                    rest,
+                )
+            })
+        }
+    };
+    let addrs = |src: &str, dst: &str| Some((src.to_string(), dst.to_string()));
+
+    // v1
+    let v1 = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET / HTTP/1.1\r\n".to_vec();
+    assert_eq!(
+        read(v1).await.unwrap(),
+        (
+            addrs("192.0.2.1:56324", "192.0.2.2:443"),
+            "GET / HTTP/1.1\r\n".to_string()
+        )
+    );
+    let v1 = b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 80\r\n".to_vec();
+    assert_eq!(
+        read(v1).await.unwrap().0,
+        addrs("[2001:db8::1]:4000", "[2001:db8::2]:80")
+    );
+    assert_eq!(read(b"PROXY UNKNOWN\r\n".to_vec()).await.unwrap().0, None);
+    assert!(read(b"PROXY TCP4 2001:db8::1 192.0.2.2 1 2\r\n".to_vec())
+        .await
+        .is_err());
+    assert!(read(b"PROXY TCP4 192.0.2.1 192.0.2.2 1\r\n".to_vec())
+        .await
+        .is_err());
+    let long = format!("PROXY UNKNOWN {}\r\n", "x".repeat(100));
+    assert!(read(long.into_bytes()).await.is_err());
+    assert!(read(b"GET / HTTP/1.1\r\n\r\n".to_vec()).await.is_err());
+
+    // v2
+    let mut v2 = V2_SIGNATURE.to_vec();
+    v2.extend([0x21, 0x11, 0, 15]);
+    v2.extend([192, 0, 2, 1, 192, 0, 2, 2, 0xdc, 0x04, 0x01, 0xbb]);
+    // TLV
+    v2.extend([0x04, 0, 0]);
+    v2.extend(b"\x16\x03\x01");
+    assert_eq!(
+        read(v2).await.unwrap(),
+        (
+            addrs("192.0.2.1:56324", "192.0.2.2:443"),
+            "\x16\x03\x01".to_string()
+        )
+    );
+    let mut v2 = V2_SIGNATURE.to_vec();
+    v2.extend([0x21, 0x21, 0, 36]);
+    v2.extend([0x20, 0x01, 0x0d, 0xb8]);
+    v2.extend([0; 11]);
+    v2.push(1);
+    v2.extend([0; 16]);
+    v2.extend([0, 80, 0, 80]);
+    assert_eq!(
+        read(v2).await.unwrap().0,
+        addrs("[2001:db8::1]:80", "[::]:80")
+    );
+    let mut local = V2_SIGNATURE.to_vec();
+    local.extend([0x20, 0x00, 0, 0]);
+    assert_eq!(read(local).await.unwrap().0, None);
+    let mut short = V2_SIGNATURE.to_vec();
+    short.extend([0x21, 0x11, 0, 4, 1, 2, 3, 4]);
+    assert!(read(short).await.is_err());
+    let mut v3 = V2_SIGNATURE.to_vec();
+    v3.extend([0x31, 0x11, 0, 0]);
+    assert!(read(v3).await.is_err());
+
+    // Nothing sent
+    let proxy = proxy.clone().timeout(Duration::from_millis(50));
+    let (_client, mut server) = tokio::io::duplex(64);
+    assert_eq!(proxy.read(&mut server).await.unwrap_err(), "Timed out");
+}
@@ -0,0 +1,121 @@
+use crate::matcher::Cidr;
+use hyper::header::{HeaderName, FORWARDED};
+use hyper::{Body, Request};
+use std::net::{IpAddr, SocketAddr};
+
+// The client address sent by trusted proxies
+#[derive(Debug, Clone)]
+pub struct RealIp {
+    trusted: Vec<Cidr>,
+    // `X-Forwarded-For`, `Forwarded` (RFC 7239) or a list like `X-Real-IP`
+    header: HeaderName,
+}
+
+impl RealIp {
+    pub fn new(trusted: Vec<Cidr>, header: HeaderName) -> Self {
+        Self { trusted, header }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    // Walk the hops from the right, the first untrusted one is the client
+    pub fn remote(&self, req: &Request<Body>, remote: SocketAddr) -> SocketAddr {
+        if !self.is_trusted(remote.ip()) {
+            return remote;
+        }
+        let mut hops = vec![];
+        for value in req.headers().get_all(&self.header) {
+            let value = match value.to_str() {
+                Ok(s) => s,
+                Err(_) => return remote,
+            };
+            for item in value.split(',') {
+                match self.header == FORWARDED {
+                    true => hops.push(forwarded_for(item)),
+                    false => hops.push(Some(item.trim())),
+                }
+            }
+        }
+
+        let mut client = remote;
+        for hop in hops.into_iter().rev() {
+            // Unknown or obfuscated, nothing before it can be trusted
+            let addr = match hop.and_then(parse_node) {
+                Some(addr) => addr,
+                None => break,
+            };
+            client = addr;
+            if !self.is_trusted(addr.ip()) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+// `for=192.0.2.60;proto=http;by=203.0.113.43`
+fn forwarded_for(element: &str) -> Option<&str> {
+    element.split(';').find_map(|pair| {
+        let (key, value) = pair.trim().split_once('=')?;
+        match key.eq_ignore_ascii_case("for") {
+            true => Some(value.trim_matches('"')),
+            false => None,
+        }
+    })
+}
+
+// `1.2.3.4`, `1.2.3.4:80`, `2001:db8::1` or `[2001:db8::1]:80`, the port is 0 if missing
+fn parse_node(s: &str) -> Option<SocketAddr> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+    let ip = s.trim_start_matches('[').trim_end_matches(']');
+    ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+}
+
+#[test]
+fn test_real_ip() {
+    let trusted = vec!["10.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()];
+    let real_ip = RealIp::new(trusted, HeaderName::from_static("x-forwarded-for"));
+    let proxy = "10.0.0.1:5000".parse().unwrap();
+    let req = |values: &[&str]| {
+        let mut req = Request::builder();
+        for value in values {
+            req = req.header("x-forwarded-for", *value);
+        }
+        req.body(Body::empty()).unwrap()
+    };
+    let ip = |req: &Request<Body>, remote| real_ip.remote(req, remote).to_string();
+
+    // Spoofed hops on the left are ignored
+    let forwarded = req(&["1.1.1.1, 2.2.2.2", "10.0.0.2"]);
+    assert_eq!(ip(&forwarded, proxy), "2.2.2.2:0");
+    assert_eq!(ip(&req(&["2.2.2.2:8000"]), proxy), "2.2.2.2:8000");
+    // All trusted, the leftmost one
+    assert_eq!(ip(&req(&["10.0.0.3, 10.0.0.2"]), proxy), "10.0.0.3:0");
+    assert_eq!(ip(&req(&["1.1.1.1, unknown"]), proxy), "10.0.0.1:5000");
+    assert_eq!(ip(&req(&[]), proxy), "10.0.0.1:5000");
+    // Not from a trusted proxy
+    let client = "3.3.3.3:5000".parse().unwrap();
+    assert_eq!(ip(&forwarded, client), "3.3.3.3:5000");
+
+    let real_ip = RealIp::new(vec!["10.0.0.0/8".parse().unwrap()], FORWARDED);
+    let req = Request::builder()
+        .header(
+            FORWARDED,
+            r#"for=1.1.1.1, For="[2001:db8::1]:4711";proto=https, for=10.0.0.5;by=10.0.0.1"#,
+        )
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(
+        real_ip.remote(&req, proxy).to_string(),
+        "[2001:db8::1]:4711"
+    );
+    let req = Request::builder()
+        .header(FORWARDED, "for=_hidden, for=10.0.0.5")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(real_ip.remote(&req, proxy).to_string(), "10.0.0.5:0");
+}
@@ -1,5 +1,5 @@
-use crate::config::Var;
-use crate::{util, ResponseExt};
+use crate::config::{Context, Var};
+use crate::{log_error, util, ResponseExt};
 use hyper::header::{HeaderValue, LOCATION};
 use hyper::{Body, Request, Response, StatusCode};
 use std::str::FromStr;
@@ -12,19 +12,33 @@ pub struct Rewrite {
 
 impl Rewrite {
     pub fn new(location: &str, status: RewriteStatus) -> Result<Self, String> {
-        let location = match Var::from(location) {
-            Var::Some(s, r) => Var::Some(s, r),
+        let location = match Var::from_str(location)? {
+            Var::Some(t) => Var::Some(t),
             Var::None(s) => Var::None(util::to_header_value(&s)?),
         };
 
         Ok(Self { location, status })
     }
 
-    pub fn response(self, req: &Request<Body>) -> Response<Body> {
-        let value = self.location.map(|s, r| {
-            let rst = r.replace(s, req);
-            HeaderValue::from_str(&rst).unwrap()
-        });
+    pub fn response(self, req: &Request<Body>, ctx: &Context) -> Response<Body> {
+        let value = match self.location {
+            Var::None(value) => value,
+            Var::Some(t) => {
+                let rst = t.render(req, ctx);
+                match HeaderValue::from_str(&rst) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        log_error!(
+                            "Invalid rewrite location '{}': {}, request_id: {}",
+                            rst,
+                            err,
+                            ctx.request_id
+                        );
+                        return Response::error(StatusCode::INTERNAL_SERVER_ERROR);
+                    }
+                }
+            }
+        };
 
         let status = match self.status {
             RewriteStatus::_301 => StatusCode::MOVED_PERMANENTLY,
@@ -37,9 +51,10 @@ impl Rewrite {
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Default)]
 pub enum RewriteStatus {
     _301,
+    #[default]
     _302,
 }
 
@@ -57,8 +72,17 @@ impl FromStr for RewriteStatus {
     }
 }
 
-impl Default for RewriteStatus {
-    fn default() -> Self {
-        RewriteStatus::_302
-    }
+#[test]
+fn test_rewrite_invalid_location() {
+    let req = Request::new(Body::empty());
+    let mut ctx = Context::default();
+    let rewrite = Rewrite::new("/user/$`jwt_sub`", RewriteStatus::_302).unwrap();
+
+    ctx.jwt.insert("sub".to_string(), "tom".to_string());
+    let res = rewrite.clone().response(&req, &ctx);
+    assert_eq!(res.headers()[LOCATION], "/user/tom");
+
+    ctx.jwt.insert("sub".to_string(), "\u{7f}".to_string());
+    let res = rewrite.response(&req, &ctx);
+    assert_eq!(Response::status(&res), StatusCode::INTERNAL_SERVER_ERROR);
 }
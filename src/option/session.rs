@@ -0,0 +1,366 @@
+use crate::config::{default, Context};
+use crate::util::{ct_eq, escape_html, get_cookie, parse_form};
+use crate::{exit, mime, ResponseExt};
+use hyper::body::HttpBody;
+use hyper::header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE, LOCATION, SET_COOKIE};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use ring::{hmac, rand::SystemRandom};
+use std::collections::HashMap;
+use std::time::Duration;
+
+// Hidden field of the login form
+pub const LOGIN_FIELD: &str = "_see_login";
+
+// Login form, served in place of the protected page
+const TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>{title}</title>
+    <style>
+        body {
+            font-family: "Segoe UI", Segoe,Tahoma,Arial, Verdana, sans-serif;
+            padding: 0 16px 0;
+            margin: 0;
+        }
+        form {
+            display: grid;
+            gap: 12px;
+            max-width: 320px;
+            margin: 64px auto;
+        }
+        h1 {
+            font-weight: normal;
+            word-wrap: break-word;
+        }
+        input {
+            height: 28px;
+            font-size: 16px;
+        }
+        p {
+            color: #d93025;
+            margin: 0;
+        }
+        @media (prefers-color-scheme: dark) {
+            body {
+                background-color: #1e2022;
+                color: #d5d5d5;
+            }
+        }
+    </style>
+</head>
+<body>
+    <form method="post">
+        <h1>{title}</h1>
+        {error}
+        <input type="hidden" name="_see_login" value="1">
+        <input name="username" placeholder="Username" autocomplete="username" required autofocus>
+        <input name="password" type="password" placeholder="Password" autocomplete="current-password" required>
+        <input type="submit" value="Log in">
+    </form>
+</body>
+</html>
+"#;
+
+// Form login with an HMAC-signed cookie
+#[derive(Debug, Clone)]
+pub struct Session {
+    key: hmac::Key,
+    cookie: String,
+    // Seconds
+    expire: u64,
+    logout: String,
+}
+
+impl Default for Session {
+    // Sessions end when the process restarts
+    fn default() -> Self {
+        let key = hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+            .unwrap_or_else(|_| exit!("Cannot generate the session key"));
+        Self {
+            key,
+            cookie: default::SESSION_COOKIE.to_string(),
+            expire: default::SESSION_EXPIRE,
+            logout: default::SESSION_LOGOUT.to_string(),
+        }
+    }
+}
+
+impl Session {
+    // Sessions are kept after restarts and shared between processes
+    pub fn secret(mut self, secret: &str) -> Self {
+        self.key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        self
+    }
+
+    pub fn cookie(mut self, name: &str) -> Result<Self, String> {
+        let valid = !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+        if !valid {
+            return Err(format!("Wrong cookie name `{}`", name));
+        }
+        self.cookie = name.to_string();
+        Ok(self)
+    }
+
+    pub fn expire(mut self, expire: Duration) -> Self {
+        self.expire = expire.as_secs();
+        self
+    }
+
+    pub fn logout(mut self, path: &str) -> Self {
+        self.logout = path.to_string();
+        self
+    }
+
+    pub fn is_logout(&self, req: &Request<Body>) -> bool {
+        req.uri().path() == self.logout
+    }
+
+    // User name of a valid cookie
+    pub fn user(&self, req: &Request<Body>, now: u64) -> Option<String> {
+        self.read(req, &self.cookie, now)
+    }
+
+    // Kept by OIDC between the redirect and the callback
+    pub fn state(&self, req: &Request<Body>, now: u64) -> Option<String> {
+        self.read(req, &self.state_name(), now)
+    }
+
+    fn state_name(&self) -> String {
+        format!("{}_state", self.cookie)
+    }
+
+    fn read(&self, req: &Request<Body>, name: &str, now: u64) -> Option<String> {
+        let raw = get_cookie(req, name)?;
+        let mut split = raw.splitn(3, '.');
+        let (value, expires, _) = (split.next()?, split.next()?, split.next()?);
+        if !ct_eq(raw.as_bytes(), self.sign(name, value, expires).as_bytes()) {
+            return None;
+        }
+        if now >= expires.parse::<u64>().ok()? {
+            return None;
+        }
+        let value = base64::decode_config(value, base64::URL_SAFE_NO_PAD).ok()?;
+        String::from_utf8(value).ok()
+    }
+
+    // VALUE.EXPIRES.SIGNATURE, the cookie name is signed too,
+    // so the state cookie cannot be used as a login
+    fn sign(&self, name: &str, value: &str, expires: &str) -> String {
+        let data = format!("{}.{}", value, expires);
+        let tag = hmac::sign(&self.key, format!("{}={}", name, data).as_bytes());
+        format!(
+            "{}.{}",
+            data,
+            base64::encode_config(tag.as_ref(), base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    pub fn user_cookie(&self, ctx: &Context, user: &str, now: u64) -> String {
+        self.set_cookie(ctx, &self.cookie, Some(user), self.expire, now)
+    }
+
+    // `None` removes the cookie
+    pub fn state_cookie(&self, ctx: &Context, state: Option<&str>, now: u64) -> String {
+        let name = self.state_name();
+        self.set_cookie(ctx, &name, state, default::SESSION_STATE_EXPIRE, now)
+    }
+
+    fn set_cookie(
+        &self,
+        ctx: &Context,
+        name: &str,
+        value: Option<&str>,
+        max_age: u64,
+        now: u64,
+    ) -> String {
+        let (value, max_age) = match value {
+            Some(value) => {
+                let value = base64::encode_config(value, base64::URL_SAFE_NO_PAD);
+                (
+                    self.sign(name, &value, &(now + max_age).to_string()),
+                    max_age,
+                )
+            }
+            None => (String::new(), 0),
+        };
+        let secure = match ctx.conn.tls {
+            Some(_) => "; Secure",
+            None => "",
+        };
+        format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+            name, value, max_age, secure
+        )
+    }
+
+    // Back to the same page with the cookie
+    pub fn login(
+        &self,
+        req: &Request<Body>,
+        ctx: &Context,
+        user: &str,
+        now: u64,
+    ) -> Response<Body> {
+        redirect(&request_uri(req), &[self.user_cookie(ctx, user, now)])
+    }
+
+    pub fn logout_response(&self, ctx: &Context) -> Response<Body> {
+        redirect("/", &[self.set_cookie(ctx, &self.cookie, None, 0, 0)])
+    }
+
+    pub fn login_page(&self, title: &str, failed: bool) -> Response<Body> {
+        let error = match failed {
+            true => "<p>Wrong username or password</p>",
+            false => "",
+        };
+        let html = TEMPLATE
+            .replace("{title}", &escape_html(title))
+            .replace("{error}", error);
+        Response::new(Body::from(html))
+            .status(StatusCode::UNAUTHORIZED)
+            .header(CONTENT_TYPE, mime::text_html())
+            .header(CACHE_CONTROL, HeaderValue::from_static("no-store"))
+    }
+}
+
+// Where to go back after the login
+pub fn request_uri(req: &Request<Body>) -> String {
+    match req.uri().path_and_query() {
+        Some(pq) => local_uri(pq.as_str()).to_string(),
+        None => "/".to_string(),
+    }
+}
+
+// Browsers read `//host` and `/\host` as another site, use `/` in place of those
+pub fn local_uri(uri: &str) -> &str {
+    match uri.as_bytes() {
+        [b'/', b'/', ..] | [b'/', b'\\', ..] => "/",
+        [b'/', ..] => uri,
+        _ => "/",
+    }
+}
+
+pub fn redirect(location: &str, cookies: &[String]) -> Response<Body> {
+    let mut res = Response::new(Body::empty()).status(StatusCode::SEE_OTHER);
+    if let Ok(location) = HeaderValue::from_str(location) {
+        res.headers_mut().insert(LOCATION, location);
+    }
+    for cookie in cookies {
+        if let Ok(cookie) = HeaderValue::from_str(cookie) {
+            res.headers_mut().append(SET_COOKIE, cookie);
+        }
+    }
+    res
+}
+
+// Fields of a urlencoded POST body, the body is consumed
+pub async fn read_form(req: &mut Request<Body>) -> Option<HashMap<String, String>> {
+    if req.method() != Method::POST {
+        return None;
+    }
+    let mut body = std::mem::take(req.body_mut());
+    let mut data = vec![];
+    while let Some(chunk) = body.data().await {
+        data.extend_from_slice(&chunk.ok()?);
+        if data.len() > default::SESSION_FORM_SIZE {
+            return None;
+        }
+    }
+    Some(parse_form(&String::from_utf8_lossy(&data)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::header::COOKIE;
+
+    fn req(cookie: &str) -> Request<Body> {
+        Request::builder()
+            .uri("/a?b=1")
+            .header(COOKIE, cookie)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn cookie() {
+        let session = Session::default().secret("secret");
+        let res = session.login(&req(""), &Context::default(), "tom", 100);
+        assert_eq!(Response::status(&res), StatusCode::SEE_OTHER);
+        assert_eq!(res.headers()[LOCATION], "/a?b=1");
+
+        let cookie = res.headers()[SET_COOKIE].to_str().unwrap();
+        assert!(cookie.ends_with(&format!(
+            "; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+            default::SESSION_EXPIRE
+        )));
+        let value = cookie.split(';').next().unwrap();
+        assert!(value.starts_with("see_session=dG9t."));
+
+        let expires = 100 + default::SESSION_EXPIRE;
+        assert_eq!(session.user(&req(value), expires - 1).unwrap(), "tom");
+        assert!(session.user(&req(value), expires).is_none());
+
+        // Another key or a changed user
+        let other = Session::default().secret("other");
+        assert!(other.user(&req(value), 100).is_none());
+        let forged = value.replace("dG9t", "YW5u");
+        assert!(session.user(&req(&forged), 100).is_none());
+        assert!(session.user(&req("see_session=a.b"), 100).is_none());
+
+        // The state cookie is not a login
+        let state = session.state_cookie(&Context::default(), Some("tom"), 100);
+        let state = state.split(';').next().unwrap();
+        assert!(state.starts_with("see_session_state=dG9t."));
+        assert_eq!(session.state(&req(state), 101).unwrap(), "tom");
+        let replayed = state.replace("see_session_state=", "see_session=");
+        assert!(session.user(&req(&replayed), 101).is_none());
+
+        // Never back to another site
+        let req = Request::builder()
+            .uri("//evil.example/x")
+            .body(Body::empty())
+            .unwrap();
+        let res = session.login(&req, &Context::default(), "tom", 100);
+        assert_eq!(res.headers()[LOCATION], "/");
+        assert_eq!(local_uri("/\\evil.example"), "/");
+        assert_eq!(local_uri("/a//b"), "/a//b");
+
+        assert!(Session::default().cookie("a b").is_err());
+        let res = session.logout_response(&Context::default());
+        assert!(res.headers()[SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .starts_with("see_session=; Path=/; Max-Age=0;"));
+    }
+
+    #[tokio::test]
+    async fn form() {
+        let mut req = Request::builder()
+            .method("POST")
+            .body(Body::from("username=tom+a&password=p%26w&_see_login=1"))
+            .unwrap();
+        let form = read_form(&mut req).await.unwrap();
+        assert_eq!(form["username"], "tom a");
+        assert_eq!(form["password"], "p&w");
+        assert!(form.contains_key(LOGIN_FIELD));
+
+        let big = "a".repeat(default::SESSION_FORM_SIZE + 1);
+        let mut req = Request::builder()
+            .method("POST")
+            .body(Body::from(big))
+            .unwrap();
+        assert!(read_form(&mut req).await.is_none());
+
+        let page = Session::default().login_page("<Admin>", true);
+        let body = hyper::body::to_bytes(page.into_body()).await.unwrap();
+        let html = String::from_utf8_lossy(&body);
+        assert!(html.contains("<h1>&lt;Admin&gt;</h1>"));
+        assert!(html.contains("Wrong username or password"));
+    }
+}
@@ -0,0 +1,317 @@
+use crate::error_log::Level;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use time::OffsetDateTime;
+use tokio::io::{AsyncWriteExt, Result};
+#[cfg(unix)]
+use tokio::net::UnixDatagram;
+use tokio::net::{TcpStream, UdpSocket};
+
+// Where the syslog messages are sent
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyslogAddr {
+    Unix(PathBuf),
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+}
+
+impl SyslogAddr {
+    // unix:/dev/log udp://127.0.0.1:514 tcp://127.0.0.1:601
+    pub fn new(s: &str) -> std::result::Result<Self, String> {
+        // The port is optional
+        let addr = |s: &str, port: u16| match s
+            .strip_prefix('[')
+            .and_then(|s| s.strip_suffix(']'))
+            .unwrap_or(s)
+            .parse::<IpAddr>()
+        {
+            Ok(ip) => Ok(SocketAddr::new(ip, port)),
+            Err(_) => s
+                .parse::<SocketAddr>()
+                .map_err(|_| format!("Cannot parse `{}` to SocketAddr", s)),
+        };
+        if let Some(path) = s.strip_prefix("unix:") {
+            Ok(SyslogAddr::Unix(PathBuf::from(path)))
+        } else if let Some(s) = s.strip_prefix("udp://") {
+            addr(s, 514).map(SyslogAddr::Udp)
+        } else if let Some(s) = s.strip_prefix("tcp://") {
+            addr(s, 601).map(SyslogAddr::Tcp)
+        } else {
+            Err(format!(
+                "Wrong syslog address `{}`, optional value: `unix:PATH` `udp://HOST:PORT` `tcp://HOST:PORT`",
+                s
+            ))
+        }
+    }
+}
+
+impl Default for SyslogAddr {
+    fn default() -> Self {
+        SyslogAddr::Unix(PathBuf::from("/dev/log"))
+    }
+}
+
+pub fn to_facility(s: &str) -> std::result::Result<u8, String> {
+    const FACILITIES: [&str; 24] = [
+        "kern",
+        "user",
+        "mail",
+        "daemon",
+        "auth",
+        "syslog",
+        "lpr",
+        "news",
+        "uucp",
+        "cron",
+        "authpriv",
+        "ftp",
+        "ntp",
+        "security",
+        "console",
+        "solaris-cron",
+        "local0",
+        "local1",
+        "local2",
+        "local3",
+        "local4",
+        "local5",
+        "local6",
+        "local7",
+    ];
+    FACILITIES
+        .iter()
+        .position(|f| *f == s)
+        .map(|i| i as u8)
+        .ok_or_else(|| format!("Unknown syslog facility `{}`", s))
+}
+
+// APP-NAME of RFC 5424, 1 to 48 printable ASCII characters
+pub fn check_tag(s: &str) -> std::result::Result<(), String> {
+    if s.is_empty() || s.len() > 48 || !s.bytes().all(|b| (33..=126).contains(&b)) {
+        return Err(format!(
+            "Wrong syslog tag `{}`, expected 1 to 48 printable ASCII characters without spaces",
+            s
+        ));
+    }
+    Ok(())
+}
+
+pub fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug => 7,
+    }
+}
+
+#[derive(Debug)]
+enum Socket {
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+// RFC 5424 messages
+#[derive(Debug)]
+pub struct Syslog {
+    addr: SyslogAddr,
+    facility: u8,
+    tag: String,
+    hostname: String,
+    socket: Option<Socket>,
+}
+
+impl Syslog {
+    pub fn new(addr: SyslogAddr, facility: u8, tag: String) -> Self {
+        Self {
+            addr,
+            facility,
+            tag,
+            hostname: hostname(),
+            socket: None,
+        }
+    }
+
+    pub fn addr(&self) -> &SyslogAddr {
+        &self.addr
+    }
+
+    pub async fn send(&mut self, level: Level, msg: &str) -> Result<()> {
+        let msg = self.format(level, OffsetDateTime::now_utc(), msg);
+        if self.socket.is_none() {
+            self.socket = Some(self.connect().await?);
+        }
+        let rst = match self.socket.as_mut() {
+            #[cfg(unix)]
+            Some(Socket::Unix(socket)) => socket.send(msg.as_bytes()).await.map(|_| ()),
+            Some(Socket::Udp(socket)) => socket.send(msg.as_bytes()).await.map(|_| ()),
+            // Octet counting, RFC 6587
+            Some(Socket::Tcp(stream)) => {
+                let frame = format!("{} {}", msg.len(), msg);
+                stream.write_all(frame.as_bytes()).await
+            }
+            None => Ok(()),
+        };
+        // Connect again on the next message
+        if rst.is_err() {
+            self.socket = None;
+        }
+        rst
+    }
+
+    async fn connect(&self) -> Result<Socket> {
+        match &self.addr {
+            #[cfg(unix)]
+            SyslogAddr::Unix(path) => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                Ok(Socket::Unix(socket))
+            }
+            #[cfg(not(unix))]
+            SyslogAddr::Unix(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Unix socket is not supported",
+            )),
+            SyslogAddr::Udp(addr) => {
+                let local = match addr {
+                    SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                    SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+                };
+                let socket = UdpSocket::bind(local).await?;
+                socket.connect(addr).await?;
+                Ok(Socket::Udp(socket))
+            }
+            SyslogAddr::Tcp(addr) => TcpStream::connect(addr).await.map(Socket::Tcp),
+        }
+    }
+
+    // <PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA MSG
+    fn format(&self, level: Level, time: OffsetDateTime, msg: &str) -> String {
+        let pri = self.facility as u16 * 8 + severity(level) as u16;
+        format!(
+            "<{}>1 {}.{:03}Z {} {} {} - - {}",
+            pri,
+            time.format("%Y-%m-%dT%H:%M:%S"),
+            time.millisecond(),
+            self.hostname,
+            self.tag,
+            std::process::id(),
+            msg
+        )
+    }
+}
+
+fn hostname() -> String {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .map(|s| s.trim().to_string())
+        .find(|s| !s.is_empty())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+#[test]
+fn test_syslog_addr() {
+    assert_eq!(
+        SyslogAddr::new("unix:/dev/log").unwrap(),
+        SyslogAddr::default()
+    );
+    assert_eq!(
+        SyslogAddr::new("udp://127.0.0.1").unwrap(),
+        SyslogAddr::Udp("127.0.0.1:514".parse().unwrap())
+    );
+    assert_eq!(
+        SyslogAddr::new("tcp://127.0.0.1:1601").unwrap(),
+        SyslogAddr::Tcp("127.0.0.1:1601".parse().unwrap())
+    );
+    assert_eq!(
+        SyslogAddr::new("udp://[::1]").unwrap(),
+        SyslogAddr::Udp("[::1]:514".parse().unwrap())
+    );
+    assert!(SyslogAddr::new("/dev/log").is_err());
+    assert!(SyslogAddr::new("udp://localhost").is_err());
+    assert_eq!(to_facility("local0").unwrap(), 16);
+    assert!(to_facility("local8").is_err());
+}
+
+#[test]
+fn test_syslog_tag() {
+    assert!(check_tag("see-web_1").is_ok());
+    assert!(check_tag(&"a".repeat(48)).is_ok());
+    assert!(check_tag(&"a".repeat(49)).is_err());
+    assert!(check_tag("my app").is_err());
+    assert!(check_tag("").is_err());
+    assert!(check_tag("café").is_err());
+}
+
+#[test]
+fn test_syslog_format() {
+    let syslog = Syslog::new(SyslogAddr::default(), 16, "see".to_string());
+    let time = OffsetDateTime::from_unix_timestamp(1391794831);
+    let msg = syslog.format(Level::Info, time, "GET /");
+    let expected = format!(
+        "<134>1 2014-02-07T17:40:31.000Z {} see {} - - GET /",
+        syslog.hostname,
+        std::process::id()
+    );
+    assert_eq!(msg, expected);
+}
+
+#[tokio::test]
+async fn test_syslog_udp() {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = SyslogAddr::Udp(server.local_addr().unwrap());
+    let mut syslog = Syslog::new(addr, 1, "see".to_string());
+    syslog.send(Level::Error, "failed").await.unwrap();
+
+    let mut buf = [0; 1024];
+    let n = server.recv(&mut buf).await.unwrap();
+    let msg = String::from_utf8_lossy(&buf[..n]);
+    assert!(msg.starts_with("<11>1 "));
+    assert!(msg.ends_with(" - - failed"));
+}
+
+#[tokio::test]
+async fn test_syslog_tcp() {
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = SyslogAddr::Tcp(listener.local_addr().unwrap());
+    let mut syslog = Syslog::new(addr, 1, "see".to_string());
+    syslog.send(Level::Info, "a").await.unwrap();
+    syslog.send(Level::Info, "b").await.unwrap();
+    drop(syslog);
+
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut data = String::new();
+    stream.read_to_string(&mut data).await.unwrap();
+
+    // LEN SP MSG LEN SP MSG
+    let (len, rest) = data.split_once(' ').unwrap();
+    let len = len.parse::<usize>().unwrap();
+    assert!(rest[..len].ends_with(" - - a"));
+    let (len, rest) = rest[len..].split_once(' ').unwrap();
+    assert_eq!(rest.len(), len.parse::<usize>().unwrap());
+    assert!(rest.ends_with(" - - b"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_syslog_unix() {
+    let path = std::env::temp_dir().join(format!("see-syslog-{}.sock", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let server = UnixDatagram::bind(&path).unwrap();
+    let mut syslog = Syslog::new(SyslogAddr::Unix(path.clone()), 16, "web".to_string());
+    syslog.send(Level::Warn, "slow").await.unwrap();
+
+    let mut buf = [0; 1024];
+    let n = server.recv(&mut buf).await.unwrap();
+    fs::remove_file(&path).unwrap();
+    let msg = String::from_utf8_lossy(&buf[..n]);
+    assert!(msg.starts_with("<132>1 "));
+    assert!(msg.contains(" web "));
+}
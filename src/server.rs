@@ -1,73 +1,87 @@
-use crate::{config::ServerConfig, connect};
+use crate::{config::ServerConfig, connect, log_error, log_info, log_warn};
 use futures_util::ready;
-use futures_util::stream::{Stream, StreamExt};
+use futures_util::stream::{self, Stream, StreamExt};
 use hyper::server::{accept::from_stream, conn::Http, Builder};
 use hyper::service::{make_service_fn, service_fn};
 use std::convert::Infallible;
-use std::net::IpAddr;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, Result};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::{ProtocolVersion, Session};
 use tokio_rustls::server::TlsStream;
 
+// Information about the accepted connection
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub remote: SocketAddr,
+    pub local: SocketAddr,
+    pub tls: Option<TlsInfo>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsInfo {
+    pub sni: String,
+    pub version: String,
+}
+
+impl Default for Connection {
+    fn default() -> Self {
+        let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        Self {
+            remote: addr,
+            local: addr,
+            tls: None,
+        }
+    }
+}
+
 pub async fn run(tcp: TcpListener, config: ServerConfig) {
     let config = Arc::new(config);
 
-    let stream = AcceptTcpStream::new(tcp).filter_map(|rst| {
+    // Each connection is set up in its own task, a slow client cannot hold up the others
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let accept = AcceptTcpStream::new(tcp).for_each({
         let config = config.clone();
-        async move {
-            let (stream, ip) = match rst {
-                Ok(val) => val,
-                // Failed to receive request
-                Err(_) => return None,
-            };
-
-            // HTTPS
-            if let Some(tls) = &config.tls {
-                let stream = match tls.clone().accept(stream).await {
-                    Ok(s) => s,
-                    // TLS connection failed
-                    Err(_) => return None,
-                };
-
-                let (_, session) = stream.get_ref();
-                // TODO
-                // Matching certificate
-                let hostname = match session.get_sni_hostname() {
-                    Some(name) => name,
-                    None => return None,
-                };
-
-                let i = config
-                    .sites
-                    .iter()
-                    .position(|site| site.host.is_match(hostname))
-                    .unwrap();
-
-                return Some(Ok::<_, hyper::Error>(HttpConnect::TlsStream(stream, ip, i)));
+        move |rst| {
+            let config = config.clone();
+            let sender = sender.clone();
+            async move {
+                match rst {
+                    Ok((stream, conn)) => {
+                        tokio::spawn(async move {
+                            if let Some(connect) = handshake(stream, conn, &config).await {
+                                let _ = sender.send(connect);
+                            }
+                        });
+                    }
+                    // Failed to receive request
+                    Err(err) => {
+                        log_error!("Accept connection on {} failed: {}", config.listen, err);
+                    }
+                }
             }
-
-            // HTTP
-            Some(Ok::<_, hyper::Error>(HttpConnect::Stream(stream, ip)))
         }
     });
+    tokio::spawn(accept);
+
+    let stream = stream::unfold(receiver, |mut receiver| async move {
+        let connect = receiver.recv().await?;
+        Some((Ok::<_, hyper::Error>(connect), receiver))
+    });
 
     let service = make_service_fn(|req: &HttpConnect| {
         let config = config.clone();
-        let (remote, site_position) = match req {
-            HttpConnect::Stream(_, ip) => (*ip, None),
-            HttpConnect::TlsStream(_, ip, i) => (*ip, Some(*i)),
+        let (conn, site_position) = match req {
+            HttpConnect::Stream(_, conn) => (conn.clone(), None),
+            HttpConnect::TlsStream(_, conn, i) => (conn.clone(), Some(*i)),
         };
         async move {
-            let service = service_fn(move |req| {
-                let sites = match site_position {
-                    Some(i) => vec![config.sites[i].clone()],
-                    None => config.sites.clone(),
-                };
-                connect(req, remote, sites)
-            });
+            let service =
+                service_fn(move |req| connect(req, conn.clone(), config.clone(), site_position));
             Ok::<_, Infallible>(service)
         }
     });
@@ -77,7 +91,94 @@ pub async fn run(tcp: TcpListener, config: ServerConfig) {
     let _ = Builder::new(from_stream(stream), http).serve(service).await;
 }
 
-// Accept stream and remote ip from TcpListener
+// Read the PROXY protocol header and finish the TLS handshake
+async fn handshake(
+    mut stream: TcpStream,
+    mut conn: Connection,
+    config: &ServerConfig,
+) -> Option<HttpConnect> {
+    // The load balancer sends the client address first
+    if let Some(proxy) = &config.proxy_protocol {
+        if !proxy.is_trusted(conn.remote.ip()) {
+            log_warn!(
+                "PROXY protocol from untrusted peer, client: {}",
+                conn.remote
+            );
+            return None;
+        }
+        match proxy.read(&mut stream).await {
+            Ok(Some((remote, local))) => {
+                conn.remote = remote;
+                conn.local = local;
+            }
+            // Health checks of the proxy itself
+            Ok(None) => {}
+            Err(err) => {
+                log_info!(
+                    "Read PROXY protocol header failed: {}, client: {}",
+                    err,
+                    conn.remote
+                );
+                return None;
+            }
+        }
+    }
+
+    // HTTPS
+    if let Some(tls) = &config.tls {
+        let stream = match tls.clone().accept(stream).await {
+            Ok(s) => s,
+            // TLS connection failed
+            Err(err) => {
+                log_warn!("TLS handshake failed: {}, client: {}", err, conn.remote);
+                return None;
+            }
+        };
+
+        let (_, session) = stream.get_ref();
+        // TODO
+        // Matching certificate
+        let hostname = session.get_sni_hostname();
+        // Without SNI only the default site can be served
+        let i = match hostname {
+            Some(name) => config.find_site(Some(name)),
+            None => config.default,
+        };
+        let i = match (i, hostname) {
+            (Some(i), _) => i,
+            (None, None) => {
+                log_warn!("TLS connection without SNI, client: {}", conn.remote);
+                return None;
+            }
+            (None, Some(hostname)) => {
+                log_warn!(
+                    "No site matches SNI '{}', client: {}",
+                    hostname,
+                    conn.remote
+                );
+                return None;
+            }
+        };
+
+        let version = match session.get_protocol_version() {
+            Some(ProtocolVersion::TLSv1_2) => "TLSv1.2".to_string(),
+            Some(ProtocolVersion::TLSv1_3) => "TLSv1.3".to_string(),
+            Some(v) => format!("{:?}", v),
+            None => String::new(),
+        };
+        conn.tls = Some(TlsInfo {
+            sni: hostname.unwrap_or_default().to_string(),
+            version,
+        });
+
+        return Some(HttpConnect::TlsStream(stream, conn, i));
+    }
+
+    // HTTP
+    Some(HttpConnect::Stream(stream, conn))
+}
+
+// Accept stream and connection addresses from TcpListener
 struct AcceptTcpStream {
     listener: TcpListener,
 }
@@ -89,19 +190,28 @@ impl AcceptTcpStream {
 }
 
 impl Stream for AcceptTcpStream {
-    type Item = Result<(TcpStream, IpAddr)>;
+    type Item = Result<(TcpStream, Connection)>;
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let poll = self.listener.poll_accept(cx);
         let rst = ready!(poll);
-        let item = rst.map(|(stream, addr)| (stream, addr.ip()));
+        let item = rst.and_then(|(stream, remote)| {
+            let local = stream.local_addr()?;
+            let conn = Connection {
+                remote,
+                local,
+                tls: None,
+            };
+            Ok((stream, conn))
+        });
         Poll::Ready(Some(item))
     }
 }
 
 // Distinguish between http and https
+#[allow(clippy::large_enum_variant)]
 pub enum HttpConnect {
-    Stream(TcpStream, IpAddr),
-    TlsStream(TlsStream<TcpStream>, IpAddr, usize),
+    Stream(TcpStream, Connection),
+    TlsStream(TlsStream<TcpStream>, Connection, usize),
 }
 
 impl AsyncRead for HttpConnect {
@@ -1,15 +1,27 @@
 use async_compression::Level;
 use globset::{Glob, GlobMatcher};
-use hyper::header::{HeaderName, HeaderValue};
-use hyper::{Method, StatusCode, Uri};
+use hyper::header::{HeaderName, HeaderValue, COOKIE, HOST};
+use hyper::http::uri::Authority;
+use hyper::{Body, Method, Request, StatusCode, Uri};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use regex::Regex;
+use std::collections::HashMap;
 use std::env;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 use time::util::validate_format_string;
+use time::{OffsetDateTime, UtcOffset};
 use tokio::fs;
 
+// Unreserved characters of RFC 3986 are not encoded
+const URL_ENCODE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
 // Encountered a fatal error
 // Print error message and exit the current process
 #[macro_export]
@@ -130,11 +142,137 @@ pub fn to_compress_level(s: &str) -> Result<Level, String> {
     }
 }
 
+// Size with an optional unit: 1024 10K 100M 1G
+pub fn to_size(s: &str) -> Result<u64, String> {
+    let (num, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => s.split_at(i),
+        None => (s, ""),
+    };
+    let unit = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => 1024 * 1024,
+        "G" | "GB" => 1024 * 1024 * 1024,
+        _ => return Err(format!("Cannot parse `{}` to size", s)),
+    };
+    num.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| format!("Cannot parse `{}` to size", s))
+}
+
+// Duration with a unit: 500ms 10s 5m 1h, seconds if no unit
+pub fn to_duration(s: &str) -> Result<Duration, String> {
+    let (num, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let err = || format!("Cannot parse `{}` to duration", s);
+    let num = num.parse::<u64>().map_err(|_| err())?;
+    let secs = |n: u64| num.checked_mul(n).map(Duration::from_secs).ok_or_else(err);
+    match unit {
+        "ms" => Ok(Duration::from_millis(num)),
+        "s" => Ok(Duration::from_secs(num)),
+        "m" => secs(60),
+        "h" => secs(60 * 60),
+        _ => Err(err()),
+    }
+}
+
+// Fall back to UTC if the local offset cannot be determined
+pub fn now_local() -> OffsetDateTime {
+    let now = OffsetDateTime::now_utc();
+    match UtcOffset::try_local_offset_at(now) {
+        Ok(offset) => now.to_offset(offset),
+        Err(_) => now,
+    }
+}
+
+// Host names are case-insensitive, and `example.com.` is `example.com`
+pub fn normalize_host(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+// Host of the URI (HTTP/2 `:authority` or absolute-form) or the `Host` header, without the port
+pub fn request_host(req: &Request<Body>) -> Result<Option<String>, String> {
+    if let Some(host) = req.uri().host() {
+        return Ok(Some(normalize_host(host)));
+    }
+    let value = match req.headers().get(HOST) {
+        Some(value) => value
+            .to_str()
+            .map_err(|_| "Invalid Host header".to_string())?,
+        None => return Ok(None),
+    };
+    if value.is_empty() {
+        return Ok(Some(String::new()));
+    }
+    let authority = value
+        .parse::<Authority>()
+        .map_err(|_| format!("Invalid Host header '{}'", value))?;
+    Ok(Some(normalize_host(authority.host())))
+}
+
+// cookie: a=1; b=2
+pub fn get_cookie<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    for header in req.headers().get_all(COOKIE) {
+        for item in header.to_str().unwrap_or_default().split(';') {
+            if let Some((key, value)) = item.trim().split_once('=') {
+                if key == name {
+                    return Some(value);
+                }
+            }
+        }
+    }
+    None
+}
+
+pub fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn urlencode(s: &str) -> String {
+    utf8_percent_encode(s, URL_ENCODE).to_string()
+}
+
+// `application/x-www-form-urlencoded`, also used by query strings
+pub fn parse_form(s: &str) -> HashMap<String, String> {
+    let decode = |s: &str| {
+        percent_decode_str(&s.replace('+', " "))
+            .decode_utf8_lossy()
+            .to_string()
+    };
+    s.split('&')
+        .filter_map(|item| item.split_once('='))
+        .map(|(key, value)| (decode(key), decode(value)))
+        .collect()
+}
+
+// The time does not depend on where the bytes differ
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 pub fn check_strftime(s: &str) -> Result<(), String> {
     validate_format_string(s).map_err(|err| format!("Cannot parse `{}` to time format\n{}", s, err))
 }
 
 #[test]
+#[allow(clippy::redundant_pattern_matching)]
 fn test_to_socket_addr() {
     assert_eq!(
         to_socket_addr("80").unwrap(),
@@ -150,3 +288,47 @@ fn test_to_socket_addr() {
     );
     assert!(matches!(to_socket_addr("err"), Err(_)));
 }
+
+#[test]
+fn test_request_host() {
+    let host = |uri: &str, header: Option<&str>| {
+        let mut req = Request::builder().uri(uri);
+        if let Some(value) = header {
+            req = req.header(HOST, value);
+        }
+        request_host(&req.body(Body::empty()).unwrap())
+    };
+    let some = |s: &str| Ok(Some(s.to_string()));
+    assert_eq!(host("/", Some("Example.COM:8080")), some("example.com"));
+    assert_eq!(host("/", Some("example.com.")), some("example.com"));
+    assert_eq!(host("/", Some("[::1]:80")), some("[::1]"));
+    assert_eq!(
+        host("https://A.example.com/", Some("b.com")),
+        some("a.example.com")
+    );
+    assert_eq!(host("/", Some("")), some(""));
+    assert_eq!(host("/", None), Ok(None));
+    assert!(host("/", Some("a b")).is_err());
+}
+
+#[test]
+fn test_to_size() {
+    assert_eq!(to_size("1024").unwrap(), 1024);
+    assert_eq!(to_size("10K").unwrap(), 10 * 1024);
+    assert_eq!(to_size("100m").unwrap(), 100 * 1024 * 1024);
+    assert_eq!(to_size("1GB").unwrap(), 1024 * 1024 * 1024);
+    assert!(to_size("").is_err());
+    assert!(to_size("M").is_err());
+    assert!(to_size("10T").is_err());
+}
+
+#[test]
+fn test_to_duration() {
+    assert_eq!(to_duration("3").unwrap(), Duration::from_secs(3));
+    assert_eq!(to_duration("500ms").unwrap(), Duration::from_millis(500));
+    assert_eq!(to_duration("2m").unwrap(), Duration::from_secs(120));
+    assert_eq!(to_duration("1h").unwrap(), Duration::from_secs(3600));
+    assert!(to_duration("ms").is_err());
+    assert!(to_duration("1d").is_err());
+    assert!(to_duration("18446744073709551615m").is_err());
+}
@@ -0,0 +1,152 @@
+use crate::config::default;
+use crate::{log_error, log_info};
+use std::fmt::{self, Debug};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+use tokio::{fs, time};
+
+pub type Loader<T> = fn(Vec<u8>) -> Result<T, String>;
+
+// Modified time and size of the file
+type Stamp = Option<(SystemTime, u64)>;
+
+// A value loaded from a file, reloaded in the background when the file changes
+// The previous value is kept if the new content cannot be loaded
+pub struct FileWatcher<T> {
+    path: PathBuf,
+    value: Arc<RwLock<Arc<T>>>,
+}
+
+impl<T> Clone for FileWatcher<T> {
+    fn clone(&self) -> Self {
+        Self {
+            path: self.path.clone(),
+            value: self.value.clone(),
+        }
+    }
+}
+
+impl<T> Debug for FileWatcher<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileWatcher")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl<T: Send + Sync + 'static> FileWatcher<T> {
+    pub fn new<P: AsRef<Path>>(path: P, load: Loader<T>) -> Result<Self, String> {
+        Self::with_interval(path, load, default::WATCH_INTERVAL)
+    }
+
+    pub fn with_interval<P: AsRef<Path>>(
+        path: P,
+        load: Loader<T>,
+        interval: Duration,
+    ) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        let stamp = std::fs::metadata(&path)
+            .ok()
+            .and_then(|meta| Some((meta.modified().ok()?, meta.len())));
+        let content = std::fs::read(&path)
+            .map_err(|err| format!("Read '{}' failed: {}", path.display(), err))?;
+        let value = Arc::new(RwLock::new(Arc::new(load(content)?)));
+
+        tokio::spawn(watch(
+            path.clone(),
+            Arc::downgrade(&value),
+            stamp,
+            load,
+            interval,
+        ));
+
+        Ok(Self { path, value })
+    }
+
+    pub fn get(&self) -> Arc<T> {
+        self.value.read().unwrap().clone()
+    }
+}
+
+// Stop when all watchers of the value are dropped
+async fn watch<T>(
+    path: PathBuf,
+    value: Weak<RwLock<Arc<T>>>,
+    mut stamp: Stamp,
+    load: Loader<T>,
+    interval: Duration,
+) {
+    loop {
+        time::sleep(interval).await;
+        let value = match value.upgrade() {
+            Some(value) => value,
+            None => return,
+        };
+
+        let current = fs::metadata(&path)
+            .await
+            .ok()
+            .and_then(|meta| Some((meta.modified().ok()?, meta.len())));
+        if current == stamp {
+            continue;
+        }
+        stamp = current;
+
+        let rst = match fs::read(&path).await {
+            Ok(content) => load(content),
+            Err(err) => Err(err.to_string()),
+        };
+        match rst {
+            Ok(new) => {
+                *value.write().unwrap() = Arc::new(new);
+                log_info!("Reloaded '{}'", path.display());
+            }
+            Err(err) => {
+                log_error!(
+                    "Reload '{}' failed, the previous content is kept: {}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_file_watcher() {
+    let path = "./test-watcher.txt";
+    std::fs::write(path, "1").unwrap();
+
+    let load: Loader<u32> = |data| {
+        String::from_utf8_lossy(&data)
+            .parse::<u32>()
+            .map_err(|err| err.to_string())
+    };
+    let watcher = FileWatcher::with_interval(path, load, Duration::from_millis(10)).unwrap();
+    assert_eq!(*watcher.get(), 1);
+
+    let wait_for = |n: u32| {
+        let watcher = watcher.clone();
+        async move {
+            for _ in 0..100 {
+                if *watcher.get() == n {
+                    return true;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+            false
+        }
+    };
+
+    std::fs::write(path, "22").unwrap();
+    assert!(wait_for(22).await);
+
+    // Invalid content keeps the previous value
+    std::fs::write(path, "a").unwrap();
+    time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(*watcher.get(), 22);
+
+    std::fs::remove_file(path).unwrap();
+    assert!(FileWatcher::new("./test-watcher-none.txt", load).is_err());
+}